@@ -3,18 +3,51 @@ use std::sync::Arc;
 use bytemuck::{Pod, Zeroable};
 use nalgebra::{Matrix4, Point3, Rotation3, Vector3};
 use vulkano::{
-    buffer::{cpu_pool::CpuBufferPoolSubbuffer, CpuBufferPool},
+    buffer::{BufferUsage, CpuBufferPool, DeviceLocalBuffer},
+    command_buffer::{AutoCommandBufferBuilder, CopyBufferInfo, PrimaryAutoCommandBuffer},
     device::Device,
-    memory::pool::StandardMemoryPool,
 };
 
+use crate::error::EngineError;
+
+// Stored in radians; constructing one names the unit the value is given in.
+#[derive(Debug, Default, Copy, Clone, PartialEq, PartialOrd)]
+pub struct Angle {
+    radians: f32,
+}
+
+impl Angle {
+    pub fn from_radians(radians: f32) -> Self {
+        Self { radians }
+    }
+
+    pub fn from_degrees(degrees: f32) -> Self {
+        Self {
+            radians: degrees.to_radians(),
+        }
+    }
+
+    pub fn radians(self) -> f32 {
+        self.radians
+    }
+
+    pub fn degrees(self) -> f32 {
+        self.radians.to_degrees()
+    }
+}
+
+// World space is right-handed with +y pointing down, like Vulkan's clip space:
+// with all angles zero the camera looks along +z, with +x to the right of the
+// screen and +y towards its bottom. `angle` holds rotations in radians about
+// the x, y and z axes, applied in that order. The field of view is the full
+// vertical angle, and the visible depth range runs from `z_near` to `z_far`.
 #[derive(Debug)]
 pub struct CameraConfiguration {
     pub position: Vector3<f32>,
     pub angle: Vector3<f32>,
 
     pub aspect: f32,
-    pub fov_y: f32,
+    pub fov_y: Angle,
     pub z_near: f32,
     pub z_far: f32,
 }
@@ -26,7 +59,7 @@ impl Default for CameraConfiguration {
             angle: Vector3::zeros(),
 
             aspect: 1.0,
-            fov_y: 45.0,
+            fov_y: Angle::from_degrees(45.0),
             z_near: 0.01,
             z_far: 100.0,
         }
@@ -41,73 +74,200 @@ pub struct CameraData {
 }
 
 impl CameraData {
-    const BASE_MATRIX: Matrix4<f32> = Matrix4::new(
-        1.0,  0.0,  0.0,  0.0,
-        0.0,  1.0,  0.0,  0.0,
-        0.0,  0.0, -0.5,  0.5,
-        0.0,  0.0,  0.0, -1.0,
-    );
-
     pub fn new(configuration: &CameraConfiguration) -> Self {
+        Self {
+            view: Self::view(configuration),
+            projection: Self::projection(configuration),
+        }
+    }
+
+    // Moves the camera to the origin, looking along +z with +y down. nalgebra
+    // calls this look-at left-handed because it assumes +y to be up.
+    fn view(configuration: &CameraConfiguration) -> Matrix4<f32> {
         let eye = Point3::from(configuration.position);
         let target = Rotation3::new(Vector3::new(0.0, 0.0, configuration.angle.z))
             * Rotation3::new(Vector3::new(0.0, configuration.angle.y, 0.0))
             * Rotation3::new(Vector3::new(configuration.angle.x, 0.0, 0.0))
             * Point3::new(0.0, 0.0, 1.0)
             + configuration.position;
-        let up = Vector3::y();
 
-        let view = Matrix4::look_at_lh(&eye, &target, &up);
+        Matrix4::look_at_lh(&eye, &target, &Vector3::y())
+    }
+
+    // Maps view space straight to Vulkan's clip space, with depth from zero at
+    // the near plane to one at the far plane.
+    fn projection(configuration: &CameraConfiguration) -> Matrix4<f32> {
+        let focal = 1.0 / (configuration.fov_y.radians() / 2.0).tan();
+        let aspect = configuration.aspect;
+        let (near, far) = (configuration.z_near, configuration.z_far);
+        let depth = far / (far - near);
 
-        let projection = Self::BASE_MATRIX
-            * Matrix4::new_perspective(
-                configuration.aspect,
-                configuration.fov_y,
-                configuration.z_near,
-                configuration.z_far,
-            );
+        #[rustfmt::skip]
+        let projection = Matrix4::new(
+            focal / aspect, 0.0,   0.0,   0.0,
+            0.0,            focal, 0.0,   0.0,
+            0.0,            0.0,   depth, -near * depth,
+            0.0,            0.0,   1.0,   0.0,
+        );
 
-        Self { view, projection }
+        projection
     }
 }
 
+// There is a uniform buffer per frame in flight, indexed by the target image
+// the frame renders to, so that a frame never overwrites data an earlier one
+// may still be reading. The buffers referenced by the recorded descriptor sets
+// never change; camera updates are copied into the buffer of each frame through
+// a fresh staging subbuffer in the command stream of that frame.
 pub struct Camera {
     configuration: CameraConfiguration,
 
-    buffer_pool: CpuBufferPool<CameraData>,
-    subbuffer: Option<Arc<CpuBufferPoolSubbuffer<CameraData, Arc<StandardMemoryPool>>>>,
+    buffers: Vec<Arc<DeviceLocalBuffer<CameraData>>>,
+    staging_buffer: CpuBufferPool<CameraData>,
+    dirty: Vec<bool>,
 }
 
 impl Camera {
-    pub fn new(device: Arc<Device>) -> Self {
+    pub fn new(device: Arc<Device>, frames: usize) -> Result<Self, EngineError> {
         let configuration = Default::default();
-        let buffer_pool = CpuBufferPool::uniform_buffer(device);
-        let subbuffer = None;
+        let buffers = (0..frames)
+            .map(|_| {
+                DeviceLocalBuffer::new(
+                    device.clone(),
+                    BufferUsage {
+                        uniform_buffer: true,
+                        transfer_dst: true,
+                        ..BufferUsage::empty()
+                    },
+                    device.active_queue_family_indices().iter().copied(),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        let staging_buffer = CpuBufferPool::upload(device);
+        let dirty = vec![true; frames];
 
-        Self {
+        Ok(Self {
             configuration,
-            buffer_pool,
-            subbuffer,
-        }
+            buffers,
+            staging_buffer,
+            dirty,
+        })
+    }
+
+    pub fn buffer(&self, frame: usize) -> Arc<DeviceLocalBuffer<CameraData>> {
+        self.buffers[frame].clone()
     }
 
-    pub fn subbuffer(
+    pub fn upload(
         &mut self,
-    ) -> Arc<CpuBufferPoolSubbuffer<CameraData, Arc<StandardMemoryPool>>> {
-        if self.subbuffer.is_none() {
-            let data = CameraData::new(&self.configuration);
-            self.subbuffer = Some(self.buffer_pool.from_data(data).unwrap());
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        frame: usize,
+    ) -> Result<(), EngineError> {
+        if self.dirty[frame] {
+            self.dirty[frame] = false;
+
+            let staging = self
+                .staging_buffer
+                .from_data(CameraData::new(&self.configuration))?;
+
+            builder
+                .copy_buffer(CopyBufferInfo::buffers(staging, self.buffers[frame].clone()))
+                .unwrap();
         }
 
-        self.subbuffer.clone().unwrap()
+        Ok(())
+    }
+
+    pub fn configuration(&self) -> &CameraConfiguration {
+        &self.configuration
     }
 
     pub fn update<F: FnOnce(&mut CameraConfiguration)>(&mut self, f: F) {
-        self.invalidate();
+        self.dirty.fill(true);
         f(&mut self.configuration);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    use super::*;
+
+    fn configuration() -> CameraConfiguration {
+        CameraConfiguration {
+            position: Vector3::new(1.0, 2.0, 3.0),
+            aspect: 1.5,
+            fov_y: Angle::from_degrees(60.0),
+            z_near: 0.1,
+            z_far: 50.0,
+            ..Default::default()
+        }
+    }
+
+    // Normalized device coordinates of a point given relative to the camera.
+    fn project(configuration: &CameraConfiguration, offset: Vector3<f32>) -> Vector3<f32> {
+        let data = CameraData::new(configuration);
+        let point = Point3::from(configuration.position + offset);
+        let clip = data.projection * data.view * point.to_homogeneous();
+
+        clip.xyz() / clip.w
+    }
+
+    #[test]
+    fn view_axis_projects_to_center() {
+        let mut configuration = configuration();
+        let ndc = project(&configuration, Vector3::new(0.0, 0.0, 10.0));
+        assert!(ndc.x.abs() < 1e-5 && ndc.y.abs() < 1e-5, "{:?}", ndc);
+
+        // Turned towards +x.
+        configuration.angle.y = FRAC_PI_2;
+        let ndc = project(&configuration, Vector3::new(10.0, 0.0, 0.0));
+        assert!(ndc.x.abs() < 1e-5 && ndc.y.abs() < 1e-5, "{:?}", ndc);
+    }
+
+    #[test]
+    fn depth_runs_from_near_to_far() {
+        let configuration = configuration();
+
+        let near = project(&configuration, Vector3::new(0.0, 0.0, configuration.z_near));
+        let far = project(&configuration, Vector3::new(0.0, 0.0, configuration.z_far));
+        let middle = project(&configuration, Vector3::new(0.0, 0.0, 5.0));
+
+        assert!(near.z.abs() < 1e-5, "{:?}", near);
+        assert!((far.z - 1.0).abs() < 1e-5, "{:?}", far);
+        assert!(near.z < middle.z && middle.z < far.z);
+    }
+
+    #[test]
+    fn x_is_right_and_y_is_down() {
+        let configuration = configuration();
+
+        let right = project(&configuration, Vector3::new(1.0, 0.0, 5.0));
+        assert!(right.x > 0.0 && right.y.abs() < 1e-5, "{:?}", right);
+
+        let down = project(&configuration, Vector3::new(0.0, 1.0, 5.0));
+        assert!(down.y > 0.0 && down.x.abs() < 1e-5, "{:?}", down);
+    }
+
+    #[test]
+    fn field_of_view_spans_the_screen() {
+        let configuration = configuration();
+        let half = (configuration.fov_y.radians() / 2.0).tan();
+
+        let bottom = project(&configuration, Vector3::new(0.0, half * 5.0, 5.0));
+        let right = project(
+            &configuration,
+            Vector3::new(half * configuration.aspect * 5.0, 0.0, 5.0),
+        );
+
+        assert!((bottom.y - 1.0).abs() < 1e-5, "{:?}", bottom);
+        assert!((right.x - 1.0).abs() < 1e-5, "{:?}", right);
+    }
 
-    pub fn invalidate(&mut self) {
-        self.subbuffer = None;
+    #[test]
+    fn angle_units() {
+        assert!((Angle::from_degrees(180.0).radians() - PI).abs() < 1e-6);
+        assert!((Angle::from_radians(FRAC_PI_2).degrees() - 90.0).abs() < 1e-4);
     }
 }
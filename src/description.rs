@@ -0,0 +1,714 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::OsStr,
+    fmt, fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use vulkano::{
+    device::{Device, Queue},
+    render_pass::RenderPass,
+    shader::ShaderCreationError,
+};
+
+use crate::{
+    camera::{Angle, CameraConfiguration},
+    error::EngineError,
+    light::{Light, LightKind},
+    material::TextureSource,
+    mesh::{Mesh, MeshSource},
+    scene::{InstanceRef, Scene},
+    shader,
+    texture::{Texture, TextureOptions},
+};
+
+#[derive(Debug)]
+pub enum SceneFileError {
+    Io(io::Error),
+    Parse(ron::error::SpannedError),
+    Serialize(ron::Error),
+    Mesh(tobj::LoadError),
+    Gltf(gltf::Error),
+    MissingMesh(MeshSource),
+    Shader(ShaderCreationError),
+    UnknownShader(String),
+    MissingShaderName,
+    MissingMeshSource,
+    InvalidParent([usize; 3]),
+    Texture(String, EngineError),
+    Engine(EngineError),
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "failed to access the scene file"),
+            Self::Parse(_) => write!(f, "failed to parse the scene file"),
+            Self::Serialize(_) => write!(f, "failed to serialize the scene"),
+            Self::Mesh(_) => write!(f, "failed to load a mesh"),
+            Self::Gltf(_) => write!(f, "failed to load a glTF file"),
+            Self::MissingMesh(source) => write!(
+                f,
+                "{} contains no mesh with index {}",
+                source.path.display(),
+                source.index
+            ),
+            Self::Shader(_) => write!(f, "failed to load a shader"),
+            Self::UnknownShader(name) => write!(f, "no shader named {:?}", name),
+            Self::MissingShaderName => write!(f, "a group has no shader name"),
+            Self::MissingMeshSource => write!(f, "an object has no mesh source"),
+            Self::InvalidParent(path) => write!(f, "invalid parent {:?}", path),
+            Self::Texture(source, _) => write!(f, "failed to load texture {}", source),
+            Self::Engine(_) => write!(f, "failed to create the scene"),
+        }
+    }
+}
+
+impl Error for SceneFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Parse(e) => Some(e),
+            Self::Serialize(e) => Some(e),
+            Self::Mesh(e) => Some(e),
+            Self::Gltf(e) => Some(e),
+            Self::Shader(e) => Some(e),
+            Self::Texture(_, e) => Some(e),
+            Self::Engine(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SceneFileError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<ron::error::SpannedError> for SceneFileError {
+    fn from(error: ron::error::SpannedError) -> Self {
+        Self::Parse(error)
+    }
+}
+
+impl From<ron::Error> for SceneFileError {
+    fn from(error: ron::Error) -> Self {
+        Self::Serialize(error)
+    }
+}
+
+impl From<tobj::LoadError> for SceneFileError {
+    fn from(error: tobj::LoadError) -> Self {
+        Self::Mesh(error)
+    }
+}
+
+impl From<gltf::Error> for SceneFileError {
+    fn from(error: gltf::Error) -> Self {
+        Self::Gltf(error)
+    }
+}
+
+impl From<ShaderCreationError> for SceneFileError {
+    fn from(error: ShaderCreationError) -> Self {
+        Self::Shader(error)
+    }
+}
+
+impl From<EngineError> for SceneFileError {
+    fn from(error: EngineError) -> Self {
+        Self::Engine(error)
+    }
+}
+
+// Angles in scene files are in radians, like everywhere else in the engine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraDescription {
+    pub position: [f32; 3],
+    pub angle: [f32; 3],
+    pub fov_y: f32,
+    pub z_near: f32,
+    pub z_far: f32,
+}
+
+// Rotations are quaternions written as [i, j, k, w].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub position: [f32; 3],
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+    // Group, object and instance index of the parent within this file, checked
+    // by `validate`.
+    #[serde(default)]
+    pub parent: Option<[usize; 3]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ObjectDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub mesh: MeshSource,
+    pub instances: Vec<InstanceDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub shader: String,
+    pub objects: Vec<ObjectDescription>,
+}
+
+// Cone angles are in radians.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LightDescription {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub kind: LightKind,
+    #[serde(default)]
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub direction: [f32; 3],
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub range: f32,
+    #[serde(default)]
+    pub inner_angle: f32,
+    #[serde(default)]
+    pub outer_angle: f32,
+    #[serde(default)]
+    pub cast_shadows: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SceneDescription {
+    // Without a camera, loading leaves the scene's camera untouched.
+    #[serde(default)]
+    pub camera: Option<CameraDescription>,
+    #[serde(default)]
+    pub lights: Vec<LightDescription>,
+    pub groups: Vec<GroupDescription>,
+}
+
+type Transform = (Vector3<f32>, UnitQuaternion<f32>, Vector3<f32>);
+
+impl SceneDescription {
+    pub fn from_scene(scene: &Scene) -> Result<Self, SceneFileError> {
+        let mut paths = HashMap::new();
+        let mut parents = Vec::new();
+        let mut groups = Vec::new();
+
+        for (g, (group_id, group)) in scene.groups().enumerate() {
+            let mut objects = Vec::new();
+
+            for (o, (object_id, object)) in group.objects().enumerate() {
+                let mut instances = Vec::new();
+
+                for (i, (instance_id, instance)) in object.instances().enumerate() {
+                    let reference = InstanceRef {
+                        group: group_id,
+                        object: object_id,
+                        instance: instance_id,
+                    };
+                    paths.insert(reference, [g, o, i]);
+                    if let Some(parent) = scene.parent(reference) {
+                        parents.push(([g, o, i], parent));
+                    }
+
+                    let rotation = instance.rotation.quaternion().coords;
+                    instances.push(InstanceDescription {
+                        name: object.instance_name(instance_id).map(String::from),
+                        position: instance.position.into(),
+                        rotation: rotation.into(),
+                        scale: instance.scale.into(),
+                        parent: None,
+                    });
+                }
+
+                objects.push(ObjectDescription {
+                    name: group.object_name(object_id).map(String::from),
+                    mesh: object
+                        .source()
+                        .cloned()
+                        .ok_or(SceneFileError::MissingMeshSource)?,
+                    instances,
+                });
+            }
+
+            groups.push(GroupDescription {
+                name: scene.group_name(group_id).map(String::from),
+                shader: group
+                    .shader_name()
+                    .map(String::from)
+                    .ok_or(SceneFileError::MissingShaderName)?,
+                objects,
+            });
+        }
+
+        for ([g, o, i], parent) in parents {
+            groups[g].objects[o].instances[i].parent = paths.get(&parent).copied();
+        }
+
+        let configuration = scene.camera().configuration();
+        let camera = Some(CameraDescription {
+            position: configuration.position.into(),
+            angle: configuration.angle.into(),
+            fov_y: configuration.fov_y.radians(),
+            z_near: configuration.z_near,
+            z_far: configuration.z_far,
+        });
+
+        let lights = scene
+            .lights()
+            .iter()
+            .map(|(id, light)| LightDescription {
+                name: scene.lights().name(id).map(String::from),
+                kind: light.kind,
+                position: light.position.into(),
+                direction: light.direction.into(),
+                color: light.color.into(),
+                intensity: light.intensity,
+                range: light.range,
+                inner_angle: light.inner_angle,
+                outer_angle: light.outer_angle,
+                cast_shadows: light.cast_shadows,
+            })
+            .collect();
+
+        Ok(Self {
+            camera,
+            lights,
+            groups,
+        })
+    }
+
+    // Maps a glTF file onto a single group drawn with `shader`. Every triangle
+    // primitive becomes an object and every node referencing a mesh becomes an
+    // instance of each of its primitives, parented like the nodes are. The
+    // transforms of nodes without a mesh are folded into their children. Mesh
+    // paths are relative to the directory of the glTF file.
+    pub fn from_gltf(path: &Path, shader: &str) -> Result<Self, SceneFileError> {
+        let gltf = gltf::Gltf::open(path)?;
+        let file = PathBuf::from(path.file_name().unwrap_or_default());
+
+        let mut objects = Vec::new();
+        let mut primitives = Vec::new();
+
+        for mesh in gltf.meshes() {
+            let supported: Vec<_> = mesh.primitives().filter(Mesh::is_supported).collect();
+
+            primitives.push(objects.len()..objects.len() + supported.len());
+            for (i, _) in supported.iter().enumerate() {
+                objects.push(ObjectDescription {
+                    name: mesh.name().map(|name| match supported.len() {
+                        1 => String::from(name),
+                        _ => format!("{}.{}", name, i),
+                    }),
+                    mesh: MeshSource {
+                        path: file.clone(),
+                        index: objects.len(),
+                    },
+                    instances: Vec::new(),
+                });
+            }
+        }
+
+        let scene = gltf.default_scene().or_else(|| gltf.scenes().next());
+        if let Some(scene) = &scene {
+            for node in scene.nodes() {
+                Self::add_gltf_node(&node, None, Self::identity(), &primitives, &mut objects);
+            }
+        }
+
+        let name = scene
+            .as_ref()
+            .and_then(|scene| scene.name())
+            .map(String::from)
+            .or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()));
+
+        Ok(Self {
+            camera: None,
+            lights: Vec::new(),
+            groups: vec![GroupDescription {
+                name,
+                shader: String::from(shader),
+                objects,
+            }],
+        })
+    }
+
+    fn add_gltf_node(
+        node: &gltf::Node,
+        parent: Option<[usize; 3]>,
+        ancestors: Transform,
+        primitives: &[Range<usize>],
+        objects: &mut [ObjectDescription],
+    ) {
+        let (translation, [i, j, k, w], scale) = node.transform().decomposed();
+        let transform = Self::compose(
+            &ancestors,
+            &(
+                translation.into(),
+                UnitQuaternion::from_quaternion(Quaternion::new(w, i, j, k)),
+                scale.into(),
+            ),
+        );
+
+        let range = node
+            .mesh()
+            .map(|mesh| primitives[mesh.index()].clone())
+            .unwrap_or_default();
+
+        if range.is_empty() {
+            for child in node.children() {
+                Self::add_gltf_node(&child, parent, transform, primitives, objects);
+            }
+            return;
+        }
+
+        let (position, rotation, scale) = transform;
+        let mut first = None;
+
+        for o in range {
+            let instances = &mut objects[o].instances;
+            first.get_or_insert([0, o, instances.len()]);
+
+            instances.push(InstanceDescription {
+                name: node.name().map(String::from),
+                position: position.into(),
+                rotation: rotation.quaternion().coords.into(),
+                scale: scale.into(),
+                parent,
+            });
+        }
+
+        for child in node.children() {
+            Self::add_gltf_node(&child, first, Self::identity(), primitives, objects);
+        }
+    }
+
+    fn identity() -> Transform {
+        (Vector3::zeros(), UnitQuaternion::identity(), Vector3::repeat(1.0))
+    }
+
+    // Exact as long as non-uniform scales are not combined with rotated
+    // children, which a translation, rotation and scale cannot express.
+    fn compose(parent: &Transform, child: &Transform) -> Transform {
+        let (parent_position, parent_rotation, parent_scale) = parent;
+        let (child_position, child_rotation, child_scale) = child;
+
+        (
+            parent_position + parent_rotation * parent_scale.component_mul(child_position),
+            parent_rotation * child_rotation,
+            parent_scale.component_mul(child_scale),
+        )
+    }
+
+    pub fn parse(source: &str) -> Result<Self, SceneFileError> {
+        Ok(ron::de::from_str(source)?)
+    }
+
+    // Checks that every parent index refers to an instance of this file.
+    // Cycles are only caught by the scene while loading.
+    pub fn validate(&self) -> Result<(), SceneFileError> {
+        for group in &self.groups {
+            for object in &group.objects {
+                for instance in &object.instances {
+                    if let Some(parent @ [g, o, i]) = instance.parent {
+                        self.groups
+                            .get(g)
+                            .and_then(|group| group.objects.get(o))
+                            .and_then(|object| object.instances.get(i))
+                            .ok_or(SceneFileError::InvalidParent(parent))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_ron(&self) -> Result<String, SceneFileError> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::default(),
+        )?)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SceneFileError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SceneFileError> {
+        Ok(fs::write(path, self.to_ron()?)?)
+    }
+
+    // Adds the described groups and lights to the scene and applies the camera
+    // settings.
+    // Relative mesh paths are resolved against `base`, usually the directory of
+    // the scene file. Textures referenced by the mesh materials are loaded
+    // along with them.
+    pub fn load(
+        &self,
+        scene: &mut Scene,
+        base: &Path,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        render_pass: Arc<RenderPass>,
+    ) -> Result<(), SceneFileError> {
+        self.validate()?;
+
+        let mut meshes: HashMap<PathBuf, Vec<Mesh>> = HashMap::new();
+        let mut textures: HashMap<(PathBuf, bool), Arc<Texture>> = HashMap::new();
+        // Embedded images are shared by all meshes of a file, so they are told
+        // apart by address.
+        let mut embedded_textures: HashMap<(usize, bool), Arc<Texture>> = HashMap::new();
+        let mut references = Vec::new();
+
+        for group_description in &self.groups {
+            let (vertex_shader, fragment_shader) =
+                shader::load(&group_description.shader, device.clone())?.ok_or_else(|| {
+                    SceneFileError::UnknownShader(group_description.shader.clone())
+                })?;
+
+            let group_id = scene.create_group(
+                group_description.name.as_deref(),
+                device.clone(),
+                vertex_shader,
+                fragment_shader,
+                render_pass.clone(),
+            )?;
+            let group = scene.get_group(group_id);
+            group.set_shader_name(&group_description.shader);
+
+            let mut group_references = Vec::new();
+
+            for object_description in &group_description.objects {
+                let source = &object_description.mesh;
+                let path = base.join(&source.path);
+
+                if !meshes.contains_key(&path) {
+                    let loaded = match path.extension().and_then(OsStr::to_str) {
+                        Some("gltf" | "glb") => Mesh::from_gltf(&path)?,
+                        _ => Mesh::open_obj(&path)?,
+                    };
+                    meshes.insert(path.clone(), loaded);
+                }
+
+                let mesh = meshes[&path]
+                    .get(source.index)
+                    .cloned()
+                    .ok_or_else(|| SceneFileError::MissingMesh(source.clone()))?;
+
+                let object_id = group.create_object(
+                    object_description.name.as_deref(),
+                    mesh,
+                    device.clone(),
+                )?;
+                let object = group.get_object(object_id);
+                object.set_source(source.clone());
+
+                let material = object.material();
+                let maps = [
+                    (Texture::BASE_COLOR, material.base_color_texture.clone(), true),
+                    (Texture::NORMAL, material.normal_texture.clone(), false),
+                ];
+
+                for (slot, source, srgb) in maps {
+                    let options = TextureOptions {
+                        srgb,
+                        ..Default::default()
+                    };
+
+                    let texture = match source {
+                        Some(TextureSource::Path(path)) => {
+                            match textures.get(&(path.clone(), srgb)) {
+                                Some(texture) => Ok(texture.clone()),
+                                None => {
+                                    Texture::open(&path, &options, device.clone(), queue.clone())
+                                        .map(|texture| {
+                                            textures.insert((path.clone(), srgb), texture.clone());
+                                            texture
+                                        })
+                                        .map_err(|error| {
+                                            SceneFileError::Texture(format!("{:?}", path), error)
+                                        })
+                                }
+                            }
+                        }
+                        Some(TextureSource::Embedded(image)) => {
+                            let key = (Arc::as_ptr(&image) as usize, srgb);
+                            match embedded_textures.get(&key) {
+                                Some(texture) => Ok(texture.clone()),
+                                None => Texture::from_image(
+                                    &image,
+                                    &options,
+                                    device.clone(),
+                                    queue.clone(),
+                                )
+                                .map(|texture| {
+                                    embedded_textures.insert(key, texture.clone());
+                                    texture
+                                })
+                                .map_err(|error| {
+                                    SceneFileError::Texture(String::from("embedded image"), error)
+                                }),
+                            }
+                        }
+                        None => continue,
+                    };
+
+                    object.set_texture(slot, Some(texture?));
+                }
+
+                let mut object_references = Vec::new();
+
+                for instance_description in &object_description.instances {
+                    let instance_id =
+                        object.create_instance(instance_description.name.as_deref());
+
+                    object.get_instance(instance_id).update(|instance| {
+                        let [i, j, k, w] = instance_description.rotation;
+
+                        instance.position = instance_description.position.into();
+                        instance.rotation =
+                            UnitQuaternion::from_quaternion(Quaternion::new(w, i, j, k));
+                        instance.scale = Vector3::from(instance_description.scale);
+                    });
+
+                    object_references.push(InstanceRef {
+                        group: group_id,
+                        object: object_id,
+                        instance: instance_id,
+                    });
+                }
+
+                group_references.push(object_references);
+            }
+
+            references.push(group_references);
+        }
+
+        for (g, group_description) in self.groups.iter().enumerate() {
+            for (o, object_description) in group_description.objects.iter().enumerate() {
+                for (i, instance_description) in object_description.instances.iter().enumerate() {
+                    if let Some(parent @ [pg, po, pi]) = instance_description.parent {
+                        scene
+                            .set_parent(references[g][o][i], Some(references[pg][po][pi]))
+                            .map_err(|_| SceneFileError::InvalidParent(parent))?;
+                    }
+                }
+            }
+        }
+
+        for light in &self.lights {
+            scene.get_lights().create_light(
+                light.name.as_deref(),
+                Light {
+                    kind: light.kind,
+                    position: light.position.into(),
+                    direction: light.direction.into(),
+                    color: light.color.into(),
+                    intensity: light.intensity,
+                    range: light.range,
+                    inner_angle: light.inner_angle,
+                    outer_angle: light.outer_angle,
+                    cast_shadows: light.cast_shadows,
+                },
+            );
+        }
+
+        if let Some(camera) = &self.camera {
+            scene.get_camera().update(|configuration: &mut CameraConfiguration| {
+                configuration.position = camera.position.into();
+                configuration.angle = camera.angle.into();
+                configuration.fov_y = Angle::from_radians(camera.fov_y);
+                configuration.z_near = camera.z_near;
+                configuration.z_far = camera.z_far;
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(position: [f32; 3], parent: Option<[usize; 3]>) -> InstanceDescription {
+        InstanceDescription {
+            name: None,
+            position,
+            rotation: [0.0, 0.6, 0.0, 0.8],
+            scale: [1.0, 2.0, 0.5],
+            parent,
+        }
+    }
+
+    fn description(parent: [usize; 3]) -> SceneDescription {
+        SceneDescription {
+            camera: Some(CameraDescription {
+                position: [0.0, -1.0, -5.0],
+                angle: [0.1, 0.2, 0.0],
+                fov_y: 1.2,
+                z_near: 0.1,
+                z_far: 100.0,
+            }),
+            lights: vec![LightDescription {
+                name: Some(String::from("sun")),
+                kind: LightKind::Spot,
+                position: [1.0, -4.0, 0.0],
+                direction: [0.0, 1.0, 0.0],
+                color: [1.0, 0.9, 0.8],
+                intensity: 2.0,
+                range: 20.0,
+                inner_angle: 0.3,
+                outer_angle: 0.5,
+                cast_shadows: true,
+            }],
+            groups: vec![GroupDescription {
+                name: Some(String::from("models")),
+                shader: String::from("default"),
+                objects: vec![ObjectDescription {
+                    name: None,
+                    mesh: MeshSource {
+                        path: PathBuf::from("cube.obj"),
+                        index: 0,
+                    },
+                    instances: vec![
+                        instance([1.0, 2.0, 3.0], None),
+                        instance([0.0, 1.0, 0.0], Some(parent)),
+                    ],
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trips_through_ron() {
+        let description = description([0, 0, 0]);
+        let parsed = SceneDescription::parse(&description.to_ron().unwrap()).unwrap();
+
+        assert_eq!(parsed, description);
+        assert!(parsed.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_out_of_range_parents() {
+        for parent in [[1, 0, 0], [0, 1, 0], [0, 0, 2]] {
+            assert!(matches!(
+                description(parent).validate(),
+                Err(SceneFileError::InvalidParent(invalid)) if invalid == parent
+            ));
+        }
+    }
+}
@@ -1,21 +1,29 @@
 use std::{sync::Arc};
 
 
-
+use image::RgbaImage;
 use vulkano::{
+    buffer::{BufferUsage, CpuAccessibleBuffer},
+    command_buffer::{
+        AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo,
+        PrimaryAutoCommandBuffer, SubpassContents,
+    },
     device::{
-        physical::{PhysicalDeviceType},
-        Device, DeviceCreateInfo, DeviceExtensions, Queue, QueueCreateInfo,
+        physical::{PhysicalDevice, PhysicalDeviceType},
+        Device, DeviceCreateInfo, DeviceExtensions, Features, Queue, QueueCreateInfo,
+    },
+    format::{ClearValue, Format},
+    image::{
+        view::ImageView, AttachmentImage, ImageAccess, ImageUsage, ImageViewAbstract,
+        SampleCount, SwapchainImage,
     },
-    format::Format,
-    image::{view::ImageView, AttachmentImage, ImageAccess, ImageUsage, SwapchainImage},
     instance::{Instance, InstanceCreateInfo},
     pipeline::{
         graphics::{
             viewport::{Viewport},
         },
     },
-    render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass},
+    render_pass::RenderPass,
     swapchain::{
         self, AcquireError, PresentInfo, PresentMode, Surface, Swapchain, SwapchainCreateInfo,
         SwapchainCreationError,
@@ -31,43 +39,34 @@ use winit::{
 };
 
 use crate::{
-    scene::{Scene},
+    error::EngineError,
+    graph::{Attachment, AttachmentSize, Pass, RenderGraph, RenderGraphBuilder},
+    postprocess::{Effect, PostProcess},
+    scene::{Scene, ScenePasses},
+    shadow::ShadowMaps,
 };
 
 pub struct Engine {
     pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
     pub scene: Scene,
     pub render_pass: Arc<RenderPass>,
     pub viewport: Viewport,
     
     event_loop: EventLoop<()>,
     surface: Arc<Surface<Window>>,
-    queue: Arc<Queue>,
     swapchain: Arc<Swapchain<Window>>,
     images: Vec<Arc<SwapchainImage<Window>>>,
-    framebuffers: Vec<Arc<Framebuffer>>,
-    depth_buffer: Arc<ImageView<AttachmentImage>>,
+    graph: RenderGraph,
 }
 
 impl Engine {
-    fn create_frambuffers(
+    fn target_views(
         images: &[Arc<SwapchainImage<Window>>],
-        render_pass: Arc<RenderPass>,
-        depth_buffer: Arc<ImageView<AttachmentImage>>,
-    ) -> Vec<Arc<Framebuffer>> {
+    ) -> Result<Vec<Arc<dyn ImageViewAbstract>>, EngineError> {
         images
             .iter()
-            .map(|image| {
-                let view = ImageView::new_default(image.clone()).unwrap();
-                Framebuffer::new(
-                    render_pass.clone(),
-                    FramebufferCreateInfo {
-                        attachments: vec![view, depth_buffer.clone()],
-                        ..Default::default()
-                    },
-                )
-                .unwrap()
-            })
+            .map(|image| Ok(ImageView::new_default(image.clone())? as Arc<dyn ImageViewAbstract>))
             .collect()
     }
 
@@ -110,11 +109,14 @@ impl Engine {
                             };
 
                         self.swapchain = new_swapchain;
-                        self.framebuffers = Self::create_frambuffers(
-                            &new_images,
-                            self.render_pass.clone(),
-                            self.depth_buffer.clone(),
-                        );
+                        self.graph
+                            .resize(
+                                self.swapchain.image_extent(),
+                                Self::target_views(&new_images).unwrap(),
+                            )
+                            .unwrap();
+                        self.images = new_images;
+                        self.scene.invalidate();
 
                         if window_resized {
                             window_resized = false;
@@ -123,12 +125,6 @@ impl Engine {
 
                             let [width, height] = self.viewport.dimensions;
                             self.scene.get_camera().update(|configuration| configuration.aspect = width / height);
-
-                            self.scene.recreate_pipeline(
-                                self.device.clone(),
-                                self.render_pass.clone(),
-                                self.viewport.clone(),
-                            );
                         }
                     }
 
@@ -159,14 +155,26 @@ impl Engine {
                         Some(fence) => fence.boxed(),
                     };
 
+                    let upload_command_buffer = self
+                        .scene
+                        .upload_command_buffer(
+                            self.device.clone(),
+                            self.queue.clone(),
+                            image_i as usize,
+                        )
+                        .unwrap();
+
                     let future = previous_future
                         .join(acquire_future)
+                        .then_execute(self.queue.clone(), upload_command_buffer)
+                        .unwrap()
                         .then_execute(
                             self.queue.clone(),
                             self.scene.command_buffers(
                                 self.device.clone(),
                                 self.queue.clone(),
-                                &self.framebuffers,
+                                &self.graph,
+                                &self.viewport,
                             )[image_i]
                                 .clone(),
                         )
@@ -199,20 +207,140 @@ impl Engine {
     }
 }
 
+pub struct HeadlessEngine {
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
+    pub scene: Scene,
+    pub render_pass: Arc<RenderPass>,
+    pub viewport: Viewport,
+
+    graph: RenderGraph,
+    target: HeadlessTarget,
+}
+
+impl HeadlessEngine {
+    const FORMAT: Format = Format::R8G8B8A8_UNORM;
+
+    pub fn resize(&mut self, dimensions: [u32; 2]) -> Result<(), EngineError> {
+        self.target = HeadlessTarget::new(self.device.clone(), dimensions)?;
+        self.graph.resize(dimensions, self.target.views()?)?;
+        self.scene.invalidate();
+
+        let [width, height] = dimensions;
+        self.viewport.dimensions = [width as f32, height as f32];
+
+        self.scene
+            .get_camera()
+            .update(|configuration| configuration.aspect = width as f32 / height as f32);
+
+        Ok(())
+    }
+
+    pub fn render(&mut self) -> Result<RgbaImage, EngineError> {
+        let upload_command_buffer = self
+            .scene
+            .upload_command_buffer(self.device.clone(), self.queue.clone(), 0)?;
+        let command_buffer = self.scene.command_buffers(
+            self.device.clone(),
+            self.queue.clone(),
+            &self.graph,
+            &self.viewport,
+        )[0]
+            .clone();
+
+        sync::now(self.device.clone())
+            .then_execute(self.queue.clone(), upload_command_buffer)?
+            .then_execute(self.queue.clone(), command_buffer)?
+            .then_execute(self.queue.clone(), self.copy_command_buffer())?
+            .then_signal_fence_and_flush()?
+            .wait(None)?;
+
+        // Nothing else uses the buffer once the fence has been waited for, and
+        // it holds exactly one RGBA texel per pixel of the target.
+        let [width, height] = self.target.image.dimensions().width_height();
+        let content = self.target.output_buffer.read().unwrap();
+
+        Ok(RgbaImage::from_raw(width, height, content.to_vec()).unwrap())
+    }
+
+    fn copy_command_buffer(&self) -> PrimaryAutoCommandBuffer {
+        let mut builder = AutoCommandBufferBuilder::primary(
+            self.device.clone(),
+            self.queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        builder
+            .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(
+                self.target.image.clone(),
+                self.target.output_buffer.clone(),
+            ))
+            .unwrap();
+
+        builder.build().unwrap()
+    }
+}
+
+struct HeadlessTarget {
+    image: Arc<AttachmentImage>,
+    output_buffer: Arc<CpuAccessibleBuffer<[u8]>>,
+}
+
+impl HeadlessTarget {
+    fn new(device: Arc<Device>, dimensions: [u32; 2]) -> Result<Self, EngineError> {
+        let image = AttachmentImage::with_usage(
+            device.clone(),
+            dimensions,
+            HeadlessEngine::FORMAT,
+            ImageUsage {
+                color_attachment: true,
+                transfer_src: true,
+                ..ImageUsage::empty()
+            },
+        )?;
+
+        let output_buffer = CpuAccessibleBuffer::from_iter(
+            device,
+            BufferUsage {
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            false,
+            (0..dimensions[0] * dimensions[1] * 4).map(|_| 0u8),
+        )?;
+
+        Ok(Self {
+            image,
+            output_buffer,
+        })
+    }
+
+    fn views(&self) -> Result<Vec<Arc<dyn ImageViewAbstract>>, EngineError> {
+        Ok(vec![ImageView::new_default(self.image.clone())? as Arc<dyn ImageViewAbstract>])
+    }
+}
+
 pub struct EngineBuilder {
     library: Arc<VulkanLibrary>,
     instance_create_info: InstanceCreateInfo,
+    post_process: Vec<Effect>,
+    samples: u32,
 }
 
 impl EngineBuilder {
-    pub fn new() -> Self {
-        let library = VulkanLibrary::new().unwrap();
+    pub fn new() -> Result<Self, EngineError> {
+        let library = VulkanLibrary::new()?;
         let instance_create_info = Default::default();
+        let post_process = Effect::default_stack();
+        let samples = 1;
         
-        Self {
+        Ok(Self {
             library,
             instance_create_info,
-        }
+            post_process,
+            samples,
+        })
     }
 
     pub fn instance_with_required_extensions(mut self) -> Self {
@@ -221,72 +349,57 @@ impl EngineBuilder {
         self
     }
 
-    pub fn build(self) -> Engine {
-        let instance = Instance::new(self.library, self.instance_create_info).unwrap();
+    // Without effects the scene is rendered straight to the target.
+    pub fn post_process(mut self, effects: Vec<Effect>) -> Self {
+        self.post_process = effects;
+        self
+    }
+
+    // Samples per pixel for the scene, such as 2, 4 or 8; lowered to what the
+    // device supports.
+    pub fn msaa(mut self, samples: u32) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn build(self) -> Result<Engine, EngineError> {
+        let instance = Instance::new(self.library, self.instance_create_info)?;
         let event_loop = EventLoop::new();
 
         let surface = WindowBuilder::new()
-            .build_vk_surface(&event_loop, instance.clone())
-            .unwrap();
+            .build_vk_surface(&event_loop, instance.clone())?;
         
-        surface.window()
-            .set_cursor_grab(CursorGrabMode::Confined)
-            .unwrap();
+        // Not every platform can confine the cursor; the engine works without it.
+        let _ = surface.window()
+            .set_cursor_grab(CursorGrabMode::Confined);
         
         let physical_extensions = DeviceExtensions {
             khr_swapchain: true,
             ..DeviceExtensions::empty()
         };
-        let (physical, family_index) = instance
-            .enumerate_physical_devices()
-            .unwrap()
-            .filter(|p| p.supported_extensions().contains(&physical_extensions))
-            .filter_map(|p| {
-                p.queue_family_properties()
-                    .iter()
-                    .enumerate()
-                    .position(|(i, q)| {
-                        q.queue_flags.graphics
-                            && p.surface_support(i as u32, &surface).unwrap_or(false)
-                    })
-                    .map(|i| (p, i as u32))
-            })
-            .min_by_key(|(p, _)| match p.properties().device_type {
-                PhysicalDeviceType::DiscreteGpu => 0,
-                PhysicalDeviceType::IntegratedGpu => 1,
-                PhysicalDeviceType::VirtualGpu => 2,
-                PhysicalDeviceType::Cpu => 3,
-                PhysicalDeviceType::Other => 4,
-                _ => 5,
-            })
-            .unwrap();
-
-        let (device, mut queues) = Device::new(
-            physical,
-            DeviceCreateInfo {
-                queue_create_infos: vec![QueueCreateInfo {
-                    queue_family_index: family_index,
-                    ..Default::default()
-                }],
-                enabled_extensions: physical_extensions,
-                ..Default::default()
-            },
-        )
-        .unwrap();
+        let (physical, family_index) = Self::select_physical_device(
+            &instance,
+            &physical_extensions,
+            |p, i| p.surface_support(i, &surface).unwrap_or(false),
+        )?;
 
-        let queue = queues.next().unwrap();
+        let (device, queue) = Self::create_device(physical, family_index, physical_extensions)?;
 
         let caps = device
             .physical_device()
-            .surface_capabilities(&surface, Default::default())
-            .unwrap();
+            .surface_capabilities(&surface, Default::default())?;
         let dimensions = surface.window().inner_size();
-        let composite_alpha = caps.supported_composite_alpha.iter().next().unwrap();
+        let composite_alpha = caps
+            .supported_composite_alpha
+            .iter()
+            .next()
+            .ok_or(EngineError::NoCompositeAlpha)?;
         let image_format = Some(
             device
                 .physical_device()
-                .surface_formats(&surface, Default::default())
-                .unwrap()[0]
+                .surface_formats(&surface, Default::default())?
+                .first()
+                .ok_or(EngineError::NoSurfaceFormat)?
                 .0,
         );
 
@@ -305,54 +418,23 @@ impl EngineBuilder {
                 present_mode: PresentMode::Fifo,
                 ..Default::default()
             },
-        )
-        .unwrap();
+        )?;
 
-        let render_pass = vulkano::single_pass_renderpass!(
+        let samples = Self::sample_count(device.physical_device(), self.samples);
+        let (mut graph, passes) = Self::create_graph(
             device.clone(),
-            attachments: {
-                color: {
-                    load: Clear,
-                    store: Store,
-                    format: swapchain.image_format(),
-                    samples: 1,
-                },
-                depth: {
-                    load: Clear,
-                    store: DontCare,
-                    format: Format::D16_UNORM,
-                    samples: 1,
-                }
-            },
-            pass: {
-                color: [color],
-                depth_stencil: {depth}
-            }
-        )
-        .unwrap();
-
-        let depth_buffer = ImageView::new_default(
-            AttachmentImage::with_usage(
-                device.clone(),
-                images[0].dimensions().width_height(),
-                Format::D16_UNORM,
-                ImageUsage {
-                    depth_stencil_attachment: true,
-                    transient_attachment: true,
-                    ..ImageUsage::empty()
-                },
-            )
-            .unwrap(),
-        )
-        .unwrap();
-
-        let framebuffers: Vec<_> =
-            Engine::create_frambuffers(&images, render_pass.clone(), depth_buffer.clone());
+            swapchain.image_format(),
+            &self.post_process,
+            samples,
+        )?;
+        graph.resize(swapchain.image_extent(), Engine::target_views(&images)?)?;
+        let render_pass = graph.render_pass(passes.opaque).unwrap();
 
 
+        let [width, height] = swapchain.image_extent();
         let viewport = Viewport {
             origin: [0.0, 0.0],
-            dimensions: [1024.0, 1024.0],
+            dimensions: [width as f32, height as f32],
             depth_range: 0.0..1.0,
         };
 
@@ -468,11 +550,12 @@ impl EngineBuilder {
         };
             */
 
-        let scene = Scene::new(
-            device.clone(),
-        );
+        let mut scene = Scene::new(device.clone(), queue.clone(), &graph, passes)?;
+        scene
+            .get_camera()
+            .update(|configuration| configuration.aspect = width as f32 / height as f32);
 
-        Engine {
+        Ok(Engine {
             event_loop,
             surface,
             device,
@@ -481,9 +564,238 @@ impl EngineBuilder {
             swapchain,
             images,
             viewport,
-            framebuffers,
             scene,
-            depth_buffer,
+            graph,
+        })
+    }
+
+    pub fn build_headless(self, dimensions: [u32; 2]) -> Result<HeadlessEngine, EngineError> {
+        let instance = Instance::new(self.library, self.instance_create_info)?;
+
+        let physical_extensions = DeviceExtensions::empty();
+        let (physical, family_index) =
+            Self::select_physical_device(&instance, &physical_extensions, |_, _| true)?;
+
+        let (device, queue) = Self::create_device(physical, family_index, physical_extensions)?;
+
+        let samples = Self::sample_count(device.physical_device(), self.samples);
+        let (mut graph, passes) = Self::create_graph(
+            device.clone(),
+            HeadlessEngine::FORMAT,
+            &self.post_process,
+            samples,
+        )?;
+        let target = HeadlessTarget::new(device.clone(), dimensions)?;
+        graph.resize(dimensions, target.views()?)?;
+        let render_pass = graph.render_pass(passes.opaque).unwrap();
+
+        let [width, height] = dimensions;
+        let viewport = Viewport {
+            origin: [0.0, 0.0],
+            dimensions: [width as f32, height as f32],
+            depth_range: 0.0..1.0,
+        };
+
+        let mut scene = Scene::new(device.clone(), queue.clone(), &graph, passes)?;
+        scene
+            .get_camera()
+            .update(|configuration| configuration.aspect = width as f32 / height as f32);
+
+        Ok(HeadlessEngine {
+            device,
+            scene,
+            render_pass,
+            viewport,
+            queue,
+            graph,
+            target,
+        })
+    }
+
+    fn select_physical_device<F: Fn(&PhysicalDevice, u32) -> bool>(
+        instance: &Arc<Instance>,
+        extensions: &DeviceExtensions,
+        queue_support: F,
+    ) -> Result<(Arc<PhysicalDevice>, u32), EngineError> {
+        let candidates: Vec<_> = instance
+            .enumerate_physical_devices()
+            .map_err(EngineError::Enumeration)?
+            .filter(|p| p.supported_extensions().contains(extensions))
+            .collect();
+
+        if candidates.is_empty() {
+            return Err(EngineError::NoDeviceWithExtensions(*extensions));
+        }
+
+        candidates
+            .into_iter()
+            .filter_map(|p| {
+                p.queue_family_properties()
+                    .iter()
+                    .enumerate()
+                    .position(|(i, q)| q.queue_flags.graphics && queue_support(&p, i as u32))
+                    .map(|i| (p, i as u32))
+            })
+            .min_by_key(|(p, _)| match p.properties().device_type {
+                PhysicalDeviceType::DiscreteGpu => 0,
+                PhysicalDeviceType::IntegratedGpu => 1,
+                PhysicalDeviceType::VirtualGpu => 2,
+                PhysicalDeviceType::Cpu => 3,
+                PhysicalDeviceType::Other => 4,
+                _ => 5,
+            })
+            .ok_or(EngineError::NoGraphicsQueue)
+    }
+
+    fn create_device(
+        physical: Arc<PhysicalDevice>,
+        family_index: u32,
+        extensions: DeviceExtensions,
+    ) -> Result<(Arc<Device>, Arc<Queue>), EngineError> {
+        // Enabled where available for anisotropic texture filtering.
+        let features = Features {
+            sampler_anisotropy: physical.supported_features().sampler_anisotropy,
+            ..Features::empty()
+        };
+
+        let (device, mut queues) = Device::new(
+            physical,
+            DeviceCreateInfo {
+                queue_create_infos: vec![QueueCreateInfo {
+                    queue_family_index: family_index,
+                    ..Default::default()
+                }],
+                enabled_extensions: extensions,
+                enabled_features: features,
+                ..Default::default()
+            },
+        )?;
+
+        let queue = queues.next().ok_or(EngineError::NoQueue)?;
+
+        Ok((device, queue))
+    }
+
+    // The highest count up to the requested one that both color and depth
+    // attachments support.
+    fn sample_count(physical: &PhysicalDevice, requested: u32) -> SampleCount {
+        let properties = physical.properties();
+        let color = properties.framebuffer_color_sample_counts;
+        let depth = properties.framebuffer_depth_sample_counts;
+
+        [
+            (SampleCount::Sample8, color.sample8 && depth.sample8),
+            (SampleCount::Sample4, color.sample4 && depth.sample4),
+            (SampleCount::Sample2, color.sample2 && depth.sample2),
+        ]
+        .into_iter()
+        .find(|&(samples, supported)| supported && samples as u32 <= requested)
+        .map_or(SampleCount::Sample1, |(samples, _)| samples)
+    }
+
+    // The shadow passes, the opaque pass drawing the scene and the post-process
+    // passes turning its HDR image into the target. Pipelines of scene groups
+    // are created for the opaque pass.
+    fn create_graph(
+        device: Arc<Device>,
+        format: Format,
+        effects: &[Effect],
+        samples: SampleCount,
+    ) -> Result<(RenderGraph, ScenePasses), EngineError> {
+        let background: ClearValue = [0.3, 0.3, 0.3, 1.0].into();
+        let mut builder = RenderGraphBuilder::new(format, background);
+        let target = builder.target();
+
+        let (shadow_map, shadows) = ShadowMaps::declare(&mut builder);
+
+        let depth = builder.attachment(
+            Some("depth"),
+            Attachment {
+                format: Format::D16_UNORM,
+                size: AttachmentSize::Target,
+                layers: 1,
+                samples,
+                clear_value: 1.0.into(),
+            },
+        );
+
+        let (output, output_format) = match effects.is_empty() {
+            true => (target, format),
+            false => {
+                let hdr = builder.attachment(
+                    Some("hdr"),
+                    Attachment {
+                        format: PostProcess::FORMAT,
+                        size: AttachmentSize::Target,
+                        layers: 1,
+                        samples: SampleCount::Sample1,
+                        clear_value: background,
+                    },
+                );
+                (hdr, PostProcess::FORMAT)
+            }
+        };
+
+        // Multisampled scenes are rendered to an image of their own, which is
+        // resolved into the output at the end of the pass.
+        let (color, resolve) = match samples {
+            SampleCount::Sample1 => (output, vec![]),
+            samples => {
+                let multisampled = builder.attachment(
+                    Some("multisampled color"),
+                    Attachment {
+                        format: output_format,
+                        size: AttachmentSize::Target,
+                        layers: 1,
+                        samples,
+                        clear_value: background,
+                    },
+                );
+                (multisampled, vec![output.into()])
+            }
+        };
+
+        let opaque = builder.pass(
+            Some("opaque"),
+            Pass {
+                color: vec![color.into()],
+                depth: Some(depth.into()),
+                resolve,
+                sampled: vec![shadow_map],
+                contents: SubpassContents::SecondaryCommandBuffers,
+            },
+        );
+
+        let post_process = PostProcess::declare(&mut builder, output, effects);
+
+        let graph = builder.build(device)?;
+        let passes = ScenePasses {
+            shadow_map,
+            shadows,
+            opaque,
+            post_process,
+        };
+
+        Ok((graph, passes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Needs a Vulkan device, software implementations such as lavapipe do.
+    #[test]
+    fn headless_resize() -> Result<(), EngineError> {
+        let mut engine = EngineBuilder::new()?.build_headless([16, 16])?;
+
+        for dimensions in [[64, 64], [256, 128], [32, 32]] {
+            engine.resize(dimensions)?;
+            let image = engine.render()?;
+
+            assert_eq!(image.dimensions(), (dimensions[0], dimensions[1]));
         }
+
+        Ok(())
     }
 }
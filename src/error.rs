@@ -0,0 +1,163 @@
+use std::{error::Error, fmt};
+
+use vulkano::{
+    command_buffer::CommandBufferExecError,
+    device::{physical::PhysicalDeviceError, DeviceCreationError, DeviceExtensions},
+    image::{view::ImageViewCreationError, ImageCreationError},
+    instance::InstanceCreationError,
+    memory::DeviceMemoryError,
+    pipeline::graphics::GraphicsPipelineCreationError,
+    render_pass::{FramebufferCreationError, RenderPassCreationError},
+    sampler::SamplerCreationError,
+    shader::ShaderCreationError,
+    swapchain::SwapchainCreationError,
+    sync::FlushError,
+    LoadingError, VulkanError,
+};
+
+#[derive(Debug)]
+pub enum EngineError {
+    Loading(LoadingError),
+    Instance(InstanceCreationError),
+    Window(vulkano_win::CreationError),
+    Vulkan(VulkanError),
+    Enumeration(VulkanError),
+    NoDeviceWithExtensions(DeviceExtensions),
+    NoGraphicsQueue,
+    Device(DeviceCreationError),
+    NoQueue,
+    Surface(PhysicalDeviceError),
+    NoSurfaceFormat,
+    NoCompositeAlpha,
+    Swapchain(SwapchainCreationError),
+    RenderPass(RenderPassCreationError),
+    Image(ImageCreationError),
+    ImageView(ImageViewCreationError),
+    Framebuffer(FramebufferCreationError),
+    Memory(DeviceMemoryError),
+    Pipeline(GraphicsPipelineCreationError),
+    Shader(ShaderCreationError),
+    Texture(image::ImageError),
+    TextureData(base64::DecodeError),
+    UnsupportedTextureEncoding(String),
+    Sampler(SamplerCreationError),
+    Flush(FlushError),
+    Execution(CommandBufferExecError),
+    MissingEntryPoint(&'static str),
+    MissingSubpass(u32),
+    InvalidRenderGraph(&'static str),
+    InvalidParent,
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Loading(_) => write!(f, "failed to load the Vulkan library"),
+            Self::Instance(_) => write!(f, "failed to create the Vulkan instance"),
+            Self::Window(_) => write!(f, "failed to create the window surface"),
+            Self::Vulkan(_) => write!(f, "a Vulkan operation failed"),
+            Self::Enumeration(_) => write!(f, "failed to enumerate physical devices"),
+            Self::NoDeviceWithExtensions(extensions) => write!(
+                f,
+                "no physical device supports the required extensions {:?}",
+                extensions
+            ),
+            Self::NoGraphicsQueue => write!(
+                f,
+                "no physical device has a graphics queue suitable for rendering"
+            ),
+            Self::Device(_) => write!(f, "failed to create the logical device"),
+            Self::NoQueue => write!(f, "the logical device returned no queue"),
+            Self::Surface(_) => write!(f, "failed to query the surface properties"),
+            Self::NoSurfaceFormat => write!(f, "the surface supports no image format"),
+            Self::NoCompositeAlpha => write!(f, "the surface supports no composite alpha mode"),
+            Self::Swapchain(_) => write!(f, "failed to create the swapchain"),
+            Self::RenderPass(_) => write!(f, "failed to create the render pass"),
+            Self::Image(_) => write!(f, "failed to create an image"),
+            Self::ImageView(_) => write!(f, "failed to create an image view"),
+            Self::Framebuffer(_) => write!(f, "failed to create a framebuffer"),
+            Self::Memory(_) => write!(f, "failed to allocate device memory"),
+            Self::Pipeline(_) => write!(f, "failed to create a graphics pipeline"),
+            Self::Shader(_) => write!(f, "failed to load a shader module"),
+            Self::Texture(_) => write!(f, "failed to decode a texture"),
+            Self::TextureData(_) => write!(f, "failed to decode a texture data URI"),
+            Self::UnsupportedTextureEncoding(header) => {
+                write!(f, "unsupported texture data URI encoding {:?}", header)
+            }
+            Self::Sampler(_) => write!(f, "failed to create a sampler"),
+            Self::Flush(_) => write!(f, "failed to submit commands to the queue"),
+            Self::Execution(_) => write!(f, "failed to execute a command buffer"),
+            Self::MissingEntryPoint(name) => {
+                write!(f, "shader module has no entry point named {:?}", name)
+            }
+            Self::MissingSubpass(index) => write!(f, "render pass has no subpass {}", index),
+            Self::InvalidRenderGraph(reason) => write!(f, "invalid render graph: {}", reason),
+            Self::InvalidParent => write!(
+                f,
+                "the parent does not exist or would introduce a cycle in the hierarchy"
+            ),
+        }
+    }
+}
+
+impl Error for EngineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Loading(e) => Some(e),
+            Self::Instance(e) => Some(e),
+            Self::Window(e) => Some(e),
+            Self::Vulkan(e) => Some(e),
+            Self::Enumeration(e) => Some(e),
+            Self::Device(e) => Some(e),
+            Self::Surface(e) => Some(e),
+            Self::Swapchain(e) => Some(e),
+            Self::RenderPass(e) => Some(e),
+            Self::Image(e) => Some(e),
+            Self::ImageView(e) => Some(e),
+            Self::Framebuffer(e) => Some(e),
+            Self::Memory(e) => Some(e),
+            Self::Pipeline(e) => Some(e),
+            Self::Shader(e) => Some(e),
+            Self::Texture(e) => Some(e),
+            Self::TextureData(e) => Some(e),
+            Self::Sampler(e) => Some(e),
+            Self::Flush(e) => Some(e),
+            Self::Execution(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_from {
+    ($($error:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl From<$error> for EngineError {
+                fn from(error: $error) -> Self {
+                    Self::$variant(error)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    LoadingError => Loading,
+    InstanceCreationError => Instance,
+    vulkano_win::CreationError => Window,
+    VulkanError => Vulkan,
+    DeviceCreationError => Device,
+    PhysicalDeviceError => Surface,
+    SwapchainCreationError => Swapchain,
+    RenderPassCreationError => RenderPass,
+    ImageCreationError => Image,
+    ImageViewCreationError => ImageView,
+    FramebufferCreationError => Framebuffer,
+    DeviceMemoryError => Memory,
+    GraphicsPipelineCreationError => Pipeline,
+    ShaderCreationError => Shader,
+    image::ImageError => Texture,
+    base64::DecodeError => TextureData,
+    SamplerCreationError => Sampler,
+    FlushError => Flush,
+    CommandBufferExecError => Execution,
+);
@@ -0,0 +1,15 @@
+// Shared by the fragment shaders of the post-processing passes, which all take
+// their parameters as a single push constant vector.
+
+layout(push_constant) uniform Post {
+    vec4 parameters;
+} post;
+
+// The w parameter is set for the pass writing a target without an sRGB format.
+vec4 encode(vec3 color) {
+    if (post.parameters.w > 0.5) {
+        color = clamp(color, 0.0, 1.0);
+        color = mix(12.92 * color, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, color));
+    }
+    return vec4(color, 1.0);
+}
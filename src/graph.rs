@@ -0,0 +1,716 @@
+use std::{collections::HashMap, sync::Arc};
+
+use vulkano::{
+    command_buffer::{
+        AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, RenderPassBeginInfo, SubpassContents,
+    },
+    device::Device,
+    format::{ClearValue, Format},
+    image::{
+        view::{ImageView, ImageViewCreateInfo, ImageViewType},
+        AttachmentImage, ImageCreateFlags, ImageDimensions, ImageLayout, ImageSubresourceRange,
+        ImageUsage, ImageViewAbstract, SampleCount, StorageImage,
+    },
+    render_pass::{
+        AttachmentDescription, AttachmentReference, Framebuffer, FramebufferCreateInfo, LoadOp,
+        RenderPass, RenderPassCreateInfo, StoreOp, SubpassDescription,
+    },
+};
+
+use crate::{
+    error::EngineError,
+    handle::{Arena, Handle},
+};
+
+pub type AttachmentId = Handle<Attachment>;
+pub type PassId = Handle<Pass>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AttachmentSize {
+    // Follows the extent of the render target.
+    Target,
+    // The extent of the render target divided by the factor.
+    Downscaled(u32),
+    Fixed([u32; 2]),
+}
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub format: Format,
+    pub size: AttachmentSize,
+    pub layers: u32,
+    // Multisampled attachments have a single layer and cannot be sampled.
+    pub samples: SampleCount,
+    // Used by the first pass writing a layer; later passes load its contents.
+    pub clear_value: ClearValue,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct AttachmentLayer {
+    pub attachment: AttachmentId,
+    pub layer: u32,
+}
+
+impl From<AttachmentId> for AttachmentLayer {
+    fn from(attachment: AttachmentId) -> Self {
+        Self {
+            attachment,
+            layer: 0,
+        }
+    }
+}
+
+// A render pass with a single subpass. It renders to the attachment layers it
+// writes and samples whole attachments written by other passes, which are
+// therefore ordered before it. Multisampled color attachments can be resolved,
+// each into the single-sampled layer at the same index of `resolve`.
+#[derive(Debug, Clone)]
+pub struct Pass {
+    pub color: Vec<AttachmentLayer>,
+    pub depth: Option<AttachmentLayer>,
+    pub resolve: Vec<AttachmentLayer>,
+    pub sampled: Vec<AttachmentId>,
+    pub contents: SubpassContents,
+}
+
+impl Pass {
+    // In the order of the attachments of the render pass and framebuffers.
+    fn writes(&self) -> impl Iterator<Item = AttachmentLayer> + '_ {
+        self.color
+            .iter()
+            .chain(&self.depth)
+            .chain(&self.resolve)
+            .copied()
+    }
+}
+
+pub struct RenderGraphBuilder {
+    attachments: Arena<Attachment>,
+    passes: Arena<Pass>,
+    target: AttachmentId,
+}
+
+impl RenderGraphBuilder {
+    // The target is the image that is presented or read back; the engine
+    // supplies one or more of them and picks one per frame.
+    pub fn new(target_format: Format, clear_value: ClearValue) -> Self {
+        let mut attachments = Arena::new();
+        let passes = Arena::new();
+        let target = attachments.insert(
+            Some("target"),
+            Attachment {
+                format: target_format,
+                size: AttachmentSize::Target,
+                layers: 1,
+                samples: SampleCount::Sample1,
+                clear_value,
+            },
+        );
+
+        Self {
+            attachments,
+            passes,
+            target,
+        }
+    }
+
+    pub fn target(&self) -> AttachmentId {
+        self.target
+    }
+
+    pub fn attachment(&mut self, name: Option<&str>, attachment: Attachment) -> AttachmentId {
+        self.attachments.insert(name, attachment)
+    }
+
+    pub fn pass(&mut self, name: Option<&str>, pass: Pass) -> PassId {
+        self.passes.insert(name, pass)
+    }
+
+    // Passes writing the same layer run in the order they were declared;
+    // everything else is ordered by what the passes sample.
+    pub fn build(self, device: Arc<Device>) -> Result<RenderGraph, EngineError> {
+        let passes: Vec<_> = self.passes.iter().collect();
+        let order = self.plan(&passes)?;
+
+        let mut compiled = Vec::with_capacity(order.len());
+        for (position, &index) in order.iter().enumerate() {
+            let (id, pass) = passes[index];
+            let earlier = order[..position].iter().map(|&index| passes[index].1);
+            let later = order[position + 1..].iter().map(|&index| passes[index].1);
+
+            let mut attachments = Vec::new();
+            let mut clear_values = Vec::new();
+
+            for write in pass.writes() {
+                let attachment = self.attachments.get(write.attachment).unwrap();
+
+                // Resolving overwrites the whole layer.
+                let resolved = pass.resolve.contains(&write);
+                let cleared = !resolved
+                    && !earlier.clone().any(|other| other.writes().any(|w| w == write));
+                let stored = write.attachment == self.target
+                    || later.clone().any(|other| {
+                        other.sampled.contains(&write.attachment)
+                            || other.writes().any(|w| w == write)
+                    });
+
+                let layout = match is_depth(attachment.format) {
+                    true => ImageLayout::DepthStencilAttachmentOptimal,
+                    false => ImageLayout::ColorAttachmentOptimal,
+                };
+
+                attachments.push(AttachmentDescription {
+                    format: Some(attachment.format),
+                    samples: attachment.samples,
+                    load_op: match (cleared, resolved) {
+                        (true, _) => LoadOp::Clear,
+                        (false, true) => LoadOp::DontCare,
+                        (false, false) => LoadOp::Load,
+                    },
+                    store_op: match stored {
+                        true => StoreOp::Store,
+                        false => StoreOp::DontCare,
+                    },
+                    initial_layout: layout,
+                    final_layout: layout,
+                    ..Default::default()
+                });
+                clear_values.push(cleared.then_some(attachment.clear_value));
+            }
+
+            let reference = |attachment: usize, layout| AttachmentReference {
+                attachment: attachment as u32,
+                layout,
+                ..Default::default()
+            };
+
+            let subpass = SubpassDescription {
+                color_attachments: (0..pass.color.len())
+                    .map(|index| Some(reference(index, ImageLayout::ColorAttachmentOptimal)))
+                    .collect(),
+                depth_stencil_attachment: pass.depth.map(|_| {
+                    reference(
+                        pass.color.len(),
+                        ImageLayout::DepthStencilAttachmentOptimal,
+                    )
+                }),
+                resolve_attachments: (0..pass.resolve.len())
+                    .map(|index| {
+                        let index = pass.color.len() + pass.depth.iter().count() + index;
+                        Some(reference(index, ImageLayout::ColorAttachmentOptimal))
+                    })
+                    .collect(),
+                ..Default::default()
+            };
+
+            let render_pass = RenderPass::new(
+                device.clone(),
+                RenderPassCreateInfo {
+                    attachments,
+                    subpasses: vec![subpass],
+                    ..Default::default()
+                },
+            )?;
+
+            compiled.push(CompiledPass {
+                id,
+                render_pass,
+                clear_values,
+                framebuffers: Vec::new(),
+            });
+        }
+
+        Ok(RenderGraph {
+            device,
+            attachments: self.attachments,
+            passes: self.passes,
+            order: compiled,
+            target: self.target,
+            extent: [0; 2],
+            images: HashMap::new(),
+        })
+    }
+
+    // Validates the passes and returns the order they run in, as indices into
+    // `passes`.
+    fn plan(&self, passes: &[(PassId, &Pass)]) -> Result<Vec<usize>, EngineError> {
+        for (_, pass) in passes {
+            self.validate(pass)?;
+
+            let written = |id: AttachmentId| {
+                passes
+                    .iter()
+                    .any(|(_, other)| other.writes().any(|write| write.attachment == id))
+            };
+            if !pass.sampled.iter().all(|&id| written(id)) {
+                return Err(EngineError::InvalidRenderGraph(
+                    "a pass samples an attachment no pass writes",
+                ));
+            }
+        }
+
+        Self::order(passes)
+    }
+
+    fn validate(&self, pass: &Pass) -> Result<(), EngineError> {
+        let mut size = None;
+        let mut samples = None;
+
+        if !pass.resolve.is_empty() && pass.resolve.len() != pass.color.len() {
+            return Err(EngineError::InvalidRenderGraph(
+                "a pass resolves only some of its color attachments",
+            ));
+        }
+
+        for write in pass.writes() {
+            let attachment = self
+                .attachments
+                .get(write.attachment)
+                .ok_or(EngineError::InvalidRenderGraph("a pass writes an unknown attachment"))?;
+
+            if write.layer >= attachment.layers {
+                return Err(EngineError::InvalidRenderGraph(
+                    "a pass writes a layer beyond those of its attachment",
+                ));
+            }
+            if is_depth(attachment.format) != (pass.depth == Some(write)) {
+                return Err(EngineError::InvalidRenderGraph(
+                    "a pass writes depth to a color attachment or color to a depth one",
+                ));
+            }
+            if pass.sampled.contains(&write.attachment) {
+                return Err(EngineError::InvalidRenderGraph(
+                    "a pass samples an attachment it writes",
+                ));
+            }
+            if size.replace(attachment.size).map_or(false, |size| size != attachment.size) {
+                return Err(EngineError::InvalidRenderGraph(
+                    "the attachments written by a pass differ in size",
+                ));
+            }
+            if attachment.samples != SampleCount::Sample1 && attachment.layers != 1 {
+                return Err(EngineError::InvalidRenderGraph(
+                    "a multisampled attachment has more than one layer",
+                ));
+            }
+
+            if pass.resolve.contains(&write) {
+                if attachment.samples != SampleCount::Sample1 {
+                    return Err(EngineError::InvalidRenderGraph(
+                        "a pass resolves into a multisampled attachment",
+                    ));
+                }
+            } else if samples.replace(attachment.samples).map_or(false, |samples| {
+                samples != attachment.samples
+            }) {
+                return Err(EngineError::InvalidRenderGraph(
+                    "the attachments rendered to by a pass differ in sample count",
+                ));
+            }
+        }
+
+        if !pass.resolve.is_empty() && samples == Some(SampleCount::Sample1) {
+            return Err(EngineError::InvalidRenderGraph(
+                "a pass resolves attachments that are not multisampled",
+            ));
+        }
+
+        if size.is_none() {
+            return Err(EngineError::InvalidRenderGraph("a pass writes no attachment"));
+        }
+        for &id in &pass.sampled {
+            match self.attachments.get(id) {
+                None => {
+                    return Err(EngineError::InvalidRenderGraph(
+                        "a pass samples an unknown attachment",
+                    ))
+                }
+                Some(attachment) if attachment.samples != SampleCount::Sample1 => {
+                    return Err(EngineError::InvalidRenderGraph(
+                        "a pass samples a multisampled attachment",
+                    ))
+                }
+                Some(_) => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Repeatedly picks the first declared pass that no remaining pass has to
+    // precede. There are only a handful of passes, so this needs no cleverness.
+    fn order(passes: &[(PassId, &Pass)]) -> Result<Vec<usize>, EngineError> {
+        let precedes = |a: usize, b: usize| {
+            let (a_pass, b_pass) = (passes[a].1, passes[b].1);
+            a_pass.writes().any(|write| {
+                b_pass.sampled.contains(&write.attachment)
+                    || (a < b && b_pass.writes().any(|other| other == write))
+            })
+        };
+
+        let mut remaining: Vec<_> = (0..passes.len()).collect();
+        let mut order = Vec::with_capacity(passes.len());
+
+        while !remaining.is_empty() {
+            let next = remaining
+                .iter()
+                .position(|&b| remaining.iter().all(|&a| a == b || !precedes(a, b)))
+                .ok_or(EngineError::InvalidRenderGraph(
+                    "passes sample each other's attachments in a cycle",
+                ))?;
+            order.push(remaining.remove(next));
+        }
+
+        Ok(order)
+    }
+}
+
+struct CompiledPass {
+    id: PassId,
+    render_pass: Arc<RenderPass>,
+    clear_values: Vec<Option<ClearValue>>,
+    // One per target image for passes writing the target, a single one otherwise.
+    framebuffers: Vec<Arc<Framebuffer>>,
+}
+
+struct AllocatedImage {
+    extent: [u32; 2],
+    view: Arc<dyn ImageViewAbstract>,
+    layers: Vec<Arc<dyn ImageViewAbstract>>,
+}
+
+// Records the passes in dependency order with load and store operations derived
+// from which passes use each attachment after another. The barriers and layout
+// transitions between passes are inserted by the command buffer builder, which
+// tracks every image the framebuffers and descriptor sets refer to.
+pub struct RenderGraph {
+    device: Arc<Device>,
+    attachments: Arena<Attachment>,
+    passes: Arena<Pass>,
+    order: Vec<CompiledPass>,
+    target: AttachmentId,
+
+    extent: [u32; 2],
+    images: HashMap<AttachmentId, AllocatedImage>,
+}
+
+impl RenderGraph {
+    // Allocates the images of all attachments sized like the target, and of the
+    // others the first time, then creates the framebuffers. Has to be called
+    // before recording and whenever the target images change.
+    pub fn resize(
+        &mut self,
+        extent: [u32; 2],
+        targets: Vec<Arc<dyn ImageViewAbstract>>,
+    ) -> Result<(), EngineError> {
+        self.extent = extent;
+
+        for (id, attachment) in self.attachments.iter() {
+            let extent = match attachment.size {
+                _ if id == self.target => continue,
+                AttachmentSize::Target => extent,
+                AttachmentSize::Downscaled(factor) => {
+                    extent.map(|size| (size / factor.max(1)).max(1))
+                }
+                AttachmentSize::Fixed(extent) => extent,
+            };
+
+            if self.images.get(&id).map_or(true, |image| image.extent != extent) {
+                let sampled = self.passes.values().any(|pass| pass.sampled.contains(&id));
+                let image = Self::create_image(self.device.clone(), attachment, extent, sampled)?;
+                self.images.insert(id, image);
+            }
+        }
+
+        for compiled in &mut self.order {
+            let pass = self.passes.get(compiled.id).unwrap();
+            let views = match pass.writes().any(|write| write.attachment == self.target) {
+                true => targets.len(),
+                false => 1,
+            };
+
+            compiled.framebuffers = (0..views)
+                .map(|index| {
+                    let attachments = pass
+                        .writes()
+                        .map(|write| match write.attachment == self.target {
+                            true => targets[index].clone(),
+                            false => {
+                                self.images[&write.attachment].layers[write.layer as usize].clone()
+                            }
+                        })
+                        .collect();
+
+                    let framebuffer = Framebuffer::new(
+                        compiled.render_pass.clone(),
+                        FramebufferCreateInfo {
+                            attachments,
+                            ..Default::default()
+                        },
+                    )?;
+
+                    Ok(framebuffer)
+                })
+                .collect::<Result<_, EngineError>>()?;
+        }
+
+        Ok(())
+    }
+
+    fn create_image(
+        device: Arc<Device>,
+        attachment: &Attachment,
+        [width, height]: [u32; 2],
+        sampled: bool,
+    ) -> Result<AllocatedImage, EngineError> {
+        let depth = is_depth(attachment.format);
+        let usage = ImageUsage {
+            color_attachment: !depth,
+            depth_stencil_attachment: depth,
+            sampled,
+            transient_attachment: !sampled,
+            ..ImageUsage::empty()
+        };
+
+        // Storage images cannot be multisampled, attachment images cannot have
+        // layers; validation keeps multisampled attachments to a single layer.
+        if attachment.samples != SampleCount::Sample1 {
+            let image = AttachmentImage::multisampled_with_usage(
+                device,
+                [width, height],
+                attachment.samples,
+                attachment.format,
+                usage,
+            )?;
+            let view: Arc<dyn ImageViewAbstract> = ImageView::new_default(image)?;
+
+            return Ok(AllocatedImage {
+                extent: [width, height],
+                view: view.clone(),
+                layers: vec![view],
+            });
+        }
+
+        let image = StorageImage::with_usage(
+            device.clone(),
+            ImageDimensions::Dim2d {
+                width,
+                height,
+                array_layers: attachment.layers,
+            },
+            attachment.format,
+            usage,
+            ImageCreateFlags::empty(),
+            device.active_queue_family_indices().iter().copied(),
+        )?;
+
+        let info = ImageViewCreateInfo::from_image(&image);
+        let layers = (0..attachment.layers)
+            .map(|layer| {
+                let view: Arc<dyn ImageViewAbstract> = ImageView::new(
+                    image.clone(),
+                    ImageViewCreateInfo {
+                        view_type: ImageViewType::Dim2d,
+                        subresource_range: ImageSubresourceRange {
+                            array_layers: layer..layer + 1,
+                            ..info.subresource_range.clone()
+                        },
+                        ..info.clone()
+                    },
+                )?;
+
+                Ok(view)
+            })
+            .collect::<Result<_, EngineError>>()?;
+
+        // Layered attachments are sampled as arrays, others as plain 2D images.
+        let view = ImageView::new(
+            image.clone(),
+            ImageViewCreateInfo {
+                view_type: match attachment.layers {
+                    1 => ImageViewType::Dim2d,
+                    _ => ImageViewType::Dim2dArray,
+                },
+                ..info
+            },
+        )?;
+
+        Ok(AllocatedImage {
+            extent: [width, height],
+            view,
+            layers,
+        })
+    }
+
+    // Begins the render pass of every pass in order, except those `skip`
+    // returns true for, and lets `record` fill it. `target` is the index of the
+    // target image to render to. Nothing is recorded before the first `resize`.
+    pub fn record<S, F>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        target: usize,
+        skip: S,
+        mut record: F,
+    ) where
+        S: Fn(PassId) -> bool,
+        F: FnMut(PassId, &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>),
+    {
+        for compiled in self.order.iter().filter(|compiled| !skip(compiled.id)) {
+            let pass = self.passes.get(compiled.id).unwrap();
+            // Passes that do not write the target have a single framebuffer.
+            let framebuffer = match compiled
+                .framebuffers
+                .get(target)
+                .or_else(|| compiled.framebuffers.last())
+            {
+                Some(framebuffer) => framebuffer,
+                None => return,
+            };
+
+            builder
+                .begin_render_pass(
+                    RenderPassBeginInfo {
+                        clear_values: compiled.clear_values.clone(),
+                        ..RenderPassBeginInfo::framebuffer(framebuffer.clone())
+                    },
+                    pass.contents,
+                )
+                .unwrap();
+
+            record(compiled.id, builder);
+
+            builder.end_render_pass().unwrap();
+        }
+    }
+
+    pub fn target(&self) -> AttachmentId {
+        self.target
+    }
+
+    pub fn attachment(&self, id: AttachmentId) -> Option<&Attachment> {
+        self.attachments.get(id)
+    }
+
+    pub fn extent(&self, id: AttachmentId) -> Option<[u32; 2]> {
+        match id == self.target {
+            true => Some(self.extent),
+            false => self.images.get(&id).map(|image| image.extent),
+        }
+    }
+
+    // The number of target images, and so of command buffers recorded per frame.
+    pub fn target_count(&self) -> usize {
+        self.order
+            .iter()
+            .map(|compiled| compiled.framebuffers.len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn render_pass(&self, pass: PassId) -> Option<Arc<RenderPass>> {
+        self.order
+            .iter()
+            .find(|compiled| compiled.id == pass)
+            .map(|compiled| compiled.render_pass.clone())
+    }
+
+    // The view to sample an attachment through, once it has been allocated.
+    pub fn view(&self, attachment: AttachmentId) -> Option<Arc<dyn ImageViewAbstract>> {
+        self.images.get(&attachment).map(|image| image.view.clone())
+    }
+}
+
+fn is_depth(format: Format) -> bool {
+    let aspects = format.aspects();
+    aspects.depth || aspects.stencil
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(graph: &mut RenderGraphBuilder) -> AttachmentId {
+        graph.attachment(
+            None,
+            Attachment {
+                format: Format::R8G8B8A8_UNORM,
+                size: AttachmentSize::Target,
+                layers: 1,
+                samples: SampleCount::Sample1,
+                clear_value: [0.0; 4].into(),
+            },
+        )
+    }
+
+    fn pass(
+        graph: &mut RenderGraphBuilder,
+        output: AttachmentId,
+        sampled: &[AttachmentId],
+    ) -> PassId {
+        graph.pass(
+            None,
+            Pass {
+                color: vec![output.into()],
+                depth: None,
+                resolve: vec![],
+                sampled: sampled.to_vec(),
+                contents: SubpassContents::Inline,
+            },
+        )
+    }
+
+    fn plan(graph: &RenderGraphBuilder) -> Result<Vec<PassId>, EngineError> {
+        let passes: Vec<_> = graph.passes.iter().collect();
+        let order = graph.plan(&passes)?;
+        Ok(order.into_iter().map(|index| passes[index].0).collect())
+    }
+
+    fn graph() -> RenderGraphBuilder {
+        RenderGraphBuilder::new(Format::B8G8R8A8_SRGB, [0.0; 4].into())
+    }
+
+    #[test]
+    fn orders_diamond_dependencies() {
+        let mut graph = graph();
+        let (a, b, c) = (color(&mut graph), color(&mut graph), color(&mut graph));
+        let target = graph.target();
+
+        // Declared in reverse, so every pass has to be moved.
+        let combine = pass(&mut graph, target, &[b, c]);
+        let right = pass(&mut graph, c, &[a]);
+        let left = pass(&mut graph, b, &[a]);
+        let source = pass(&mut graph, a, &[]);
+
+        assert_eq!(plan(&graph).unwrap(), vec![source, right, left, combine]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut graph = graph();
+        let (a, b) = (color(&mut graph), color(&mut graph));
+        let target = graph.target();
+
+        pass(&mut graph, a, &[b]);
+        pass(&mut graph, b, &[a]);
+        pass(&mut graph, target, &[a]);
+
+        assert!(matches!(
+            plan(&graph),
+            Err(EngineError::InvalidRenderGraph(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_sampling_unwritten_attachments() {
+        let mut graph = graph();
+        let unwritten = color(&mut graph);
+        let target = graph.target();
+
+        pass(&mut graph, target, &[unwritten]);
+
+        assert!(matches!(
+            plan(&graph),
+            Err(EngineError::InvalidRenderGraph(_))
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
+
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Handle<T> {
+    fn new(index: u32, generation: u32) -> Self {
+        Self {
+            index,
+            generation,
+            marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn index(&self) -> usize {
+        self.index as usize
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({}v{})", self.index, self.generation)
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+    name: Option<String>,
+}
+
+// Slots are reused after removal with a bumped generation, so a handle to a
+// removed value never resolves to whatever took its place.
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+    names: HashMap<String, Handle<T>>,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        let slots = Vec::new();
+        let free = Vec::new();
+        let names = HashMap::new();
+
+        Self { slots, free, names }
+    }
+
+    // A name that is already taken moves to the new value; the previous value
+    // stays reachable through its handle.
+    pub fn insert(&mut self, name: Option<&str>, value: T) -> Handle<T> {
+        let handle = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                Handle::new(index, slot.generation)
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                    name: None,
+                });
+                Handle::new(self.slots.len() as u32 - 1, 0)
+            }
+        };
+
+        if let Some(name) = name {
+            if let Some(previous) = self.names.insert(String::from(name), handle) {
+                self.slots[previous.index as usize].name = None;
+            }
+            self.slots[handle.index as usize].name = Some(String::from(name));
+        }
+
+        handle
+    }
+
+    pub fn remove(&mut self, handle: Handle<T>) -> Option<T> {
+        let slot = self.slot_mut(handle)?;
+        let value = slot.value.take();
+        let name = slot.name.take();
+        slot.generation = slot.generation.wrapping_add(1);
+
+        if let Some(name) = name {
+            self.names.remove(&name);
+        }
+        self.free.push(handle.index);
+
+        value
+    }
+
+    pub fn get(&self, handle: Handle<T>) -> Option<&T> {
+        self.slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        self.slot_mut(handle).and_then(|slot| slot.value.as_mut())
+    }
+
+    pub fn contains(&self, handle: Handle<T>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    pub fn find(&self, name: &str) -> Option<Handle<T>> {
+        self.names.get(name).copied()
+    }
+
+    pub fn name(&self, handle: Handle<T>) -> Option<&str> {
+        self.slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+            .and_then(|slot| slot.name.as_deref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.value.take().is_some() {
+                slot.name = None;
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(index as u32);
+            }
+        }
+        self.names.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Handle<T>, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value
+                .as_ref()
+                .map(|value| (Handle::new(index as u32, slot.generation), value))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Handle<T>, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            let generation = slot.generation;
+            slot.value
+                .as_mut()
+                .map(|value| (Handle::new(index as u32, generation), value))
+        })
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> + Clone {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().filter_map(|slot| slot.value.as_mut())
+    }
+
+    fn slot_mut(&mut self, handle: Handle<T>) -> Option<&mut Slot<T>> {
+        self.slots
+            .get_mut(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation && slot.value.is_some())
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_handles_resolve_to_nothing() {
+        let mut arena = Arena::new();
+        let handle = arena.insert(None, 1);
+
+        assert_eq!(arena.remove(handle), Some(1));
+        assert_eq!(arena.get(handle), None);
+        assert!(!arena.contains(handle));
+        assert_eq!(arena.remove(handle), None);
+        assert!(arena.is_empty());
+    }
+
+    #[test]
+    fn reused_slots_bump_the_generation() {
+        let mut arena = Arena::new();
+        let old = arena.insert(None, 1);
+        arena.remove(old);
+        let new = arena.insert(None, 2);
+
+        assert_eq!(old.index(), new.index());
+        assert_ne!(old, new);
+        assert_eq!(arena.get(old), None);
+        assert_eq!(arena.get_mut(old), None);
+        assert_eq!(arena.remove(old), None);
+        assert_eq!(arena.get(new), Some(&2));
+    }
+
+    #[test]
+    fn names_move_to_the_latest_value() {
+        let mut arena = Arena::new();
+        let first = arena.insert(Some("cube"), 1);
+        let second = arena.insert(Some("cube"), 2);
+
+        assert_eq!(arena.find("cube"), Some(second));
+        assert_eq!(arena.name(first), None);
+        assert_eq!(arena.name(second), Some("cube"));
+        assert_eq!(arena.get(first), Some(&1));
+
+        // Removing the value that lost its name leaves the name alone.
+        arena.remove(first);
+        assert_eq!(arena.find("cube"), Some(second));
+    }
+
+    #[test]
+    fn find_fails_after_remove_and_clear() {
+        let mut arena = Arena::new();
+        let cube = arena.insert(Some("cube"), 1);
+        arena.insert(Some("sphere"), 2);
+
+        arena.remove(cube);
+        assert_eq!(arena.find("cube"), None);
+        assert!(arena.find("sphere").is_some());
+
+        arena.clear();
+        assert_eq!(arena.find("sphere"), None);
+        assert!(arena.is_empty());
+
+        let reused = arena.insert(None, 3);
+        assert_eq!(arena.name(reused), None);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{collections::HashMap, hash::Hash};
+
+use nalgebra::Matrix4;
+
+use crate::error::EngineError;
+
+// Parent links between instances, kept apart from the scene so the world
+// matrices can be worked out from local matrices alone. Instances without a
+// parent are not stored.
+pub struct Hierarchy<K> {
+    parents: HashMap<K, K>,
+}
+
+impl<K: Copy + Eq + Hash> Hierarchy<K> {
+    pub fn new() -> Self {
+        let parents = HashMap::new();
+
+        Self { parents }
+    }
+
+    pub fn parent(&self, child: K) -> Option<K> {
+        self.parents.get(&child).copied()
+    }
+
+    // Fails without changing anything if the child is the parent or one of its
+    // ancestors.
+    pub fn set_parent(&mut self, child: K, parent: Option<K>) -> Result<(), EngineError> {
+        match parent {
+            Some(parent) => {
+                let mut ancestor = Some(parent);
+                while let Some(current) = ancestor {
+                    if current == child {
+                        return Err(EngineError::InvalidParent);
+                    }
+                    ancestor = self.parent(current);
+                }
+
+                self.parents.insert(child, parent);
+            }
+            None => {
+                self.parents.remove(&child);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Returns the world matrix of every child and of its ancestors, given the
+    // local matrices of the instances that still exist. Links from removed children are dropped; a
+    // child whose parent is gone keeps its local matrix.
+    pub fn world_matrices<F: Fn(K) -> Option<Matrix4<f32>>>(
+        &mut self,
+        local: F,
+    ) -> HashMap<K, Matrix4<f32>> {
+        self.parents.retain(|child, _| local(*child).is_some());
+
+        let mut worlds = HashMap::new();
+        for &child in self.parents.keys() {
+            Self::world_matrix(child, &self.parents, &local, &mut worlds);
+        }
+
+        worlds
+    }
+
+    fn world_matrix<F: Fn(K) -> Option<Matrix4<f32>>>(
+        instance: K,
+        parents: &HashMap<K, K>,
+        local: &F,
+        worlds: &mut HashMap<K, Matrix4<f32>>,
+    ) -> Matrix4<f32> {
+        if let Some(world) = worlds.get(&instance) {
+            return *world;
+        }
+
+        let matrix = local(instance).unwrap_or_else(Matrix4::identity);
+        let world = match parents.get(&instance) {
+            Some(&parent) if local(parent).is_some() => {
+                Self::world_matrix(parent, parents, local, worlds) * matrix
+            }
+            _ => matrix,
+        };
+
+        worlds.insert(instance, world);
+        world
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for Hierarchy<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Point3, Vector3};
+
+    use super::*;
+
+    fn translation(x: f32, y: f32, z: f32) -> Matrix4<f32> {
+        Matrix4::new_translation(&Vector3::new(x, y, z))
+    }
+
+    fn locals(matrices: &[Matrix4<f32>]) -> impl Fn(usize) -> Option<Matrix4<f32>> + '_ {
+        move |index| matrices.get(index).copied()
+    }
+
+    #[test]
+    fn chains_compose_parent_first() {
+        let matrices = [
+            translation(1.0, 0.0, 0.0),
+            Matrix4::new_scaling(2.0),
+            translation(0.0, 1.0, 0.0),
+        ];
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.set_parent(1, Some(0)).unwrap();
+        hierarchy.set_parent(2, Some(1)).unwrap();
+
+        let worlds = hierarchy.world_matrices(locals(&matrices));
+
+        // The grandchild's origin moves to (0, 1, 0), scales to (0, 2, 0) and
+        // moves to (1, 2, 0).
+        let origin = worlds[&2].transform_point(&Point3::origin());
+        assert_eq!(origin, Point3::new(1.0, 2.0, 0.0));
+        assert_eq!(worlds[&1], matrices[0] * matrices[1]);
+        assert_eq!(worlds[&0], matrices[0]);
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.set_parent(1, Some(0)).unwrap();
+        hierarchy.set_parent(2, Some(1)).unwrap();
+
+        assert!(matches!(
+            hierarchy.set_parent(0, Some(2)),
+            Err(EngineError::InvalidParent)
+        ));
+        assert!(matches!(
+            hierarchy.set_parent(0, Some(0)),
+            Err(EngineError::InvalidParent)
+        ));
+        assert_eq!(hierarchy.parent(0), None);
+    }
+
+    #[test]
+    fn orphans_keep_their_local_matrix() {
+        let matrices = [translation(1.0, 0.0, 0.0), translation(0.0, 1.0, 0.0)];
+        let mut hierarchy = Hierarchy::new();
+        hierarchy.set_parent(1, Some(0)).unwrap();
+        hierarchy.set_parent(5, Some(0)).unwrap();
+
+        // The parent of instance 0, instance 2, does not exist.
+        hierarchy.set_parent(0, Some(2)).unwrap();
+        let worlds = hierarchy.world_matrices(locals(&matrices));
+        assert_eq!(worlds[&0], matrices[0]);
+        assert_eq!(worlds[&1], matrices[0] * matrices[1]);
+
+        // Instance 5 is gone too, so its link is dropped.
+        assert_eq!(hierarchy.parent(5), None);
+        assert!(!worlds.contains_key(&5));
+    }
+}
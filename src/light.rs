@@ -0,0 +1,329 @@
+use std::{f32::consts::FRAC_PI_2, sync::Arc};
+
+use bytemuck::{Pod, Zeroable};
+use nalgebra::{Matrix4, Orthographic3, Perspective3, Point3, Vector3, Vector4};
+use serde::{Deserialize, Serialize};
+use vulkano::{
+    buffer::{BufferUsage, CpuBufferPool, DeviceLocalBuffer},
+    command_buffer::{AutoCommandBufferBuilder, CopyBufferInfo, PrimaryAutoCommandBuffer},
+    device::Device,
+};
+
+use crate::{
+    camera::CameraConfiguration,
+    error::EngineError,
+    handle::{Arena, Handle},
+    shadow::ShadowMaps,
+};
+
+pub type LightId = Handle<Light>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LightKind {
+    Directional,
+    Point,
+    Spot,
+}
+
+// Directional lights only use the direction, point lights only the position and
+// range; spot lights use all of them. Cone angles are half angles in radians,
+// with the light fading out between the inner and the outer one.
+//
+// Directional and spot lights can cast shadows. The shadow of a directional
+// light covers the cube of half size `range` around the camera.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: Vector3<f32>,
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    pub range: f32,
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    pub cast_shadows: bool,
+}
+
+impl Light {
+    const SHADOW_NEAR: f32 = 0.05;
+    // Cone angles close to zero or a right angle make for degenerate shadow
+    // projections, and so do ranges that do not reach past the near plane.
+    const MIN_SHADOW_ANGLE: f32 = 0.01;
+    const MIN_SHADOW_RANGE: f32 = 2.0 * Self::SHADOW_NEAR;
+
+    pub fn directional(direction: Vector3<f32>, color: Vector3<f32>) -> Self {
+        Self {
+            kind: LightKind::Directional,
+            direction,
+            color,
+            ..Default::default()
+        }
+    }
+
+    pub fn point(position: Vector3<f32>, color: Vector3<f32>, range: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            position,
+            color,
+            range,
+            ..Default::default()
+        }
+    }
+
+    pub fn spot(
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        range: f32,
+        angle: f32,
+    ) -> Self {
+        Self {
+            kind: LightKind::Spot,
+            position,
+            direction,
+            color,
+            range,
+            inner_angle: angle * 0.8,
+            outer_angle: angle,
+            ..Default::default()
+        }
+    }
+
+    // Maps world space to the clip space of the shadow map, with depth in the
+    // zero to one range Vulkan expects.
+    fn shadow_matrix(&self, camera: &CameraConfiguration) -> Matrix4<f32> {
+        #[rustfmt::skip]
+        let depth_range = Matrix4::new(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 0.5, 0.5,
+            0.0, 0.0, 0.0, 1.0,
+        );
+
+        let direction = self
+            .direction
+            .try_normalize(f32::EPSILON)
+            .unwrap_or_else(Vector3::y);
+        let up = match direction.y.abs() > 0.99 {
+            true => Vector3::x(),
+            false => Vector3::y(),
+        };
+
+        let range = self.range.max(Self::MIN_SHADOW_RANGE);
+        let angle = self
+            .outer_angle
+            .clamp(Self::MIN_SHADOW_ANGLE, FRAC_PI_2 - Self::MIN_SHADOW_ANGLE);
+
+        let (eye, projection) = match self.kind {
+            LightKind::Spot => (
+                self.position,
+                Perspective3::new(1.0, 2.0 * angle, Self::SHADOW_NEAR, range).to_homogeneous(),
+            ),
+            _ => (
+                camera.position - direction * range,
+                Orthographic3::new(-range, range, -range, range, 0.0, 2.0 * range)
+                    .to_homogeneous(),
+            ),
+        };
+
+        let eye = Point3::from(eye);
+        let view = Matrix4::look_at_rh(&eye, &(eye + direction), &up);
+
+        depth_range * projection * view
+    }
+
+    fn data(&self, shadow: Option<usize>) -> LightData {
+        let kind = match self.kind {
+            LightKind::Directional => 0.0,
+            LightKind::Point => 1.0,
+            LightKind::Spot => 2.0,
+        };
+
+        LightData {
+            position: self.position.push(kind),
+            direction: self
+                .direction
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_else(Vector3::y)
+                .push(self.range),
+            color: (self.color * self.intensity).push(0.0),
+            cone: Vector4::new(self.inner_angle.cos(), self.outer_angle.cos(), 0.0, 0.0),
+            shadow: Vector4::new(shadow.map_or(-1.0, |layer| layer as f32), 0.0, 0.0, 0.0),
+        }
+    }
+}
+
+impl Default for Light {
+    fn default() -> Self {
+        Self {
+            kind: LightKind::Directional,
+            position: Vector3::zeros(),
+            direction: Vector3::y(),
+            color: Vector3::repeat(1.0),
+            intensity: 1.0,
+            range: 10.0,
+            inner_angle: 0.0,
+            outer_angle: 0.0,
+            cast_shadows: false,
+        }
+    }
+}
+
+// Laid out for a std140 uniform block: the kind is stored in the w component of
+// the position, the range in that of the direction. The shadow map layer, or -1,
+// is the x component of `shadow`.
+#[repr(C)]
+#[derive(Default, Debug, Copy, Clone, Zeroable, Pod)]
+pub struct LightData {
+    position: Vector4<f32>,
+    direction: Vector4<f32>,
+    color: Vector4<f32>,
+    cone: Vector4<f32>,
+    shadow: Vector4<f32>,
+}
+
+#[repr(C)]
+#[derive(Default, Debug, Copy, Clone, Zeroable, Pod)]
+pub struct LightsData {
+    count: u32,
+    padding: [u32; 3],
+    lights: [LightData; Lights::MAX_LIGHTS],
+    shadow_matrices: [Matrix4<f32>; ShadowMaps::MAX_SHADOWS],
+}
+
+// Uploaded like the camera, into a uniform buffer per frame in flight in the
+// command stream of that frame. Directional shadows follow the camera, so
+// lights are uploaded every frame while any casts one.
+pub struct Lights {
+    lights: Arena<Light>,
+
+    buffers: Vec<Arc<DeviceLocalBuffer<LightsData>>>,
+    staging_buffer: CpuBufferPool<LightsData>,
+    dirty: Vec<bool>,
+}
+
+impl Lights {
+    // Lights beyond this many are ignored by the shaders.
+    pub const MAX_LIGHTS: usize = 16;
+
+    pub fn new(device: Arc<Device>, frames: usize) -> Result<Self, EngineError> {
+        let lights = Arena::new();
+        let buffers = (0..frames)
+            .map(|_| {
+                DeviceLocalBuffer::new(
+                    device.clone(),
+                    BufferUsage {
+                        uniform_buffer: true,
+                        transfer_dst: true,
+                        ..BufferUsage::empty()
+                    },
+                    device.active_queue_family_indices().iter().copied(),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        let staging_buffer = CpuBufferPool::upload(device);
+        let dirty = vec![true; frames];
+
+        Ok(Self {
+            lights,
+            buffers,
+            staging_buffer,
+            dirty,
+        })
+    }
+
+    pub fn buffer(&self, frame: usize) -> Arc<DeviceLocalBuffer<LightsData>> {
+        self.buffers[frame].clone()
+    }
+
+    pub fn upload(
+        &mut self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        camera: &CameraConfiguration,
+        frame: usize,
+    ) -> Result<(), EngineError> {
+        if self.dirty[frame] || self.shadow_count() > 0 {
+            self.dirty[frame] = false;
+
+            let mut data = LightsData::default();
+            let mut shadows = 0;
+
+            for (slot, light) in data.lights.iter_mut().zip(self.lights.values()) {
+                let shadow = match Self::casts_shadow(light) && shadows < ShadowMaps::MAX_SHADOWS {
+                    true => {
+                        data.shadow_matrices[shadows] = light.shadow_matrix(camera);
+                        shadows += 1;
+                        Some(shadows - 1)
+                    }
+                    false => None,
+                };
+
+                *slot = light.data(shadow);
+                data.count += 1;
+            }
+
+            let staging = self.staging_buffer.from_data(data)?;
+
+            builder
+                .copy_buffer(CopyBufferInfo::buffers(staging, self.buffers[frame].clone()))
+                .unwrap();
+        }
+
+        Ok(())
+    }
+
+    // The number of shadow map layers in use, assigned to shadow-casting lights
+    // in the order they are uploaded.
+    pub fn shadow_count(&self) -> usize {
+        self.lights
+            .values()
+            .take(Self::MAX_LIGHTS)
+            .filter(|light| Self::casts_shadow(light))
+            .count()
+            .min(ShadowMaps::MAX_SHADOWS)
+    }
+
+    fn casts_shadow(light: &Light) -> bool {
+        light.cast_shadows && light.kind != LightKind::Point
+    }
+
+    pub fn create_light(&mut self, name: Option<&str>, light: Light) -> LightId {
+        self.dirty.fill(true);
+        self.lights.insert(name, light)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (LightId, &Light)> {
+        self.lights.iter()
+    }
+
+    pub fn name(&self, id: LightId) -> Option<&str> {
+        self.lights.name(id)
+    }
+
+    pub fn get_light(&mut self, id: LightId) -> &mut Light {
+        self.try_get_light(id).unwrap()
+    }
+
+    pub fn try_get_light(&mut self, id: LightId) -> Option<&mut Light> {
+        self.dirty.fill(true);
+        self.lights.get_mut(id)
+    }
+
+    pub fn light(&self, id: LightId) -> Option<&Light> {
+        self.lights.get(id)
+    }
+
+    pub fn find_light(&self, name: &str) -> Option<LightId> {
+        self.lights.find(name)
+    }
+
+    pub fn remove_light(&mut self, id: LightId) -> bool {
+        self.dirty.fill(true);
+        self.lights.remove(id).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.dirty.fill(true);
+        self.lights.clear();
+    }
+}
@@ -1,85 +1,233 @@
 #![feature(trait_alias)]
 
-use std::{collections::HashMap, path::Path, time::Instant, f32::consts::PI};
+use std::{collections::HashMap, error::Error, path::{Path, PathBuf}, sync::Arc, time::Instant, f32::consts::PI};
 
+use description::SceneDescription;
 use engine::EngineBuilder;
-use mesh::{Mesh, Vertex};
-use nalgebra::{Matrix4, Vector3, Vector4, clamp, Vector2};
+use light::Light;
+use mesh::{Mesh, MeshSource};
+use nalgebra::{Matrix4, UnitQuaternion, Vector3, Vector4, clamp};
+use postprocess::{Effect, Tonemapping};
 use rand::Rng;
+use scene::{Object, Scene};
+use vulkano::{
+    device::{Device, Queue},
+    render_pass::RenderPass,
+};
 use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 
 mod camera;
+mod description;
 mod engine;
+mod error;
+mod graph;
+mod handle;
+mod hierarchy;
+mod light;
+mod material;
 mod mesh;
+mod postprocess;
 mod scene;
 mod shader;
+mod shadow;
 mod texture;
 
-fn main() {
-    let mut engine = EngineBuilder::new()
-        .instance_with_required_extensions()
-        .build();
-
+fn populate(
+    scene: &mut Scene,
+    device: Arc<Device>,
+    render_pass: Arc<RenderPass>,
+) -> Result<(), Box<dyn Error>> {
     let cube_mesh = Mesh::from_obj(
         include_str!("cube.obj"),
         &HashMap::from([(Path::new("cube.mtl"), include_str!("cube.mtl"))]),
-    )
-    .unwrap()
+    )?
     .first()
-    .unwrap()
+    .ok_or("cube.obj contains no mesh")?
     .clone();
 
-    engine
-        .scene
-        .create_group(
-            "basic",
-            engine.device.clone(),
-            crate::shader::simple::vertex::load(engine.device.clone()).unwrap(),
-            crate::shader::simple::fragment::load(engine.device.clone()).unwrap(),
-            engine.render_pass.clone(),
-            engine.viewport.clone(),
-        )
-        .create_object("cube", cube_mesh, engine.device.clone())
-        .create_instance("0").update(|instance| {
-            instance.position = Vector3::new(-1.0, -1.0, 0.0);
-            instance.scale = 0.5;
-        });
-
-    let plane_mesh = Mesh {
-        vertices: vec![
-            Vertex { coord: Vector3::new(-1.0, -1.0,  0.0), normal: Vector3::new(0.0, 0.0, -1.0), tex_coord: Vector2::new(0.0, 0.0) },
-            Vertex { coord: Vector3::new(-1.0,  1.0,  0.0), normal: Vector3::new(0.0, 0.0, -1.0), tex_coord: Vector2::new(0.0, 0.0) },
-            Vertex { coord: Vector3::new( 1.0,  1.0,  0.0), normal: Vector3::new(0.0, 0.0, -1.0), tex_coord: Vector2::new(0.0, 0.0) },
-            Vertex { coord: Vector3::new( 1.0, -1.0,  0.0), normal: Vector3::new(0.0, 0.0, -1.0), tex_coord: Vector2::new(0.0, 0.0) },
-        ],
-        indices: vec![
-            0, 1, 2,
-            0, 2, 3
-        ],
-    };
+    let (vertex_shader, fragment_shader) =
+        shader::load("simple", device.clone())?.ok_or("unknown shader")?;
+    let basic = scene.create_group(
+        Some("basic"),
+        device.clone(),
+        vertex_shader,
+        fragment_shader,
+        render_pass.clone(),
+    )?;
+    scene.get_group(basic).set_shader_name("simple");
+    let cube = scene
+        .get_group(basic)
+        .create_object(Some("cube"), cube_mesh, device.clone())?;
+    let object = scene.get_group(basic).get_object(cube);
+    object.set_source(MeshSource {
+        path: PathBuf::from("cube.obj"),
+        index: 0,
+    });
+    let instance = object.create_instance(None);
+    object.get_instance(instance).update(|instance| {
+        instance.position = Vector3::new(-1.0, -1.0, 0.0);
+        instance.scale = Vector3::repeat(0.5);
+    });
 
-    engine
-        .scene
-        .create_group(
-            "raymarch",
-            engine.device.clone(),
-            crate::shader::raymarch::vertex::load(engine.device.clone()).unwrap(),
-            crate::shader::raymarch::fragment::load(engine.device.clone()).unwrap(),
-            engine.render_pass.clone(),
-            engine.viewport.clone()
-        )
-        .create_object("plane", plane_mesh, engine.device.clone())
-        .create_instance("0")
-        .update(|instance| {
-            instance.position = Vector3::new(1.0, -1.0, 0.0);
-            instance.scale = 0.5;
-        });
+    let plane_mesh = Mesh::from_obj(
+        include_str!("plane.obj"),
+        &HashMap::from([(Path::new("plane.mtl"), include_str!("plane.mtl"))]),
+    )?
+    .first()
+    .ok_or("plane.obj contains no mesh")?
+    .clone();
+
+    let (vertex_shader, fragment_shader) =
+        shader::load("raymarch", device.clone())?.ok_or("unknown shader")?;
+    let raymarch = scene.create_group(
+        Some("raymarch"),
+        device.clone(),
+        vertex_shader,
+        fragment_shader,
+        render_pass,
+    )?;
+    scene.get_group(raymarch).set_shader_name("raymarch");
+    let plane = scene
+        .get_group(raymarch)
+        .create_object(Some("plane"), plane_mesh, device)?;
+    let object = scene.get_group(raymarch).get_object(plane);
+    object.set_source(MeshSource {
+        path: PathBuf::from("plane.obj"),
+        index: 0,
+    });
+    let instance = object.create_instance(None);
+    object.get_instance(instance).update(|instance| {
+        instance.position = Vector3::new(1.0, -1.0, 0.0);
+        instance.scale = Vector3::repeat(0.5);
+    });
         
-    engine
-        .scene
+    scene
         .get_camera()
         .update(|configuration| configuration.position = Vector3::new(1.0, -0.5, -2.0));
 
+    Ok(())
+}
+
+// Builds the scene from the file given with `--scene` or `--gltf`, or the demo
+// scene otherwise, and writes it to the file given with `--save`.
+fn load(
+    scene: &mut Scene,
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    render_pass: Arc<RenderPass>,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = argument("--scene") {
+        let path = PathBuf::from(path);
+        let base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        SceneDescription::open(&path)?.load(scene, &base, device, queue, render_pass)?;
+    } else if let Some(path) = argument("--gltf") {
+        let path = PathBuf::from(path);
+        let base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        SceneDescription::from_gltf(&path, "simple")?
+            .load(scene, &base, device, queue, render_pass)?;
+    } else {
+        populate(scene, device, render_pass)?;
+    }
+
+    // Scenes without lights would only show their ambient term.
+    if scene.lights().iter().next().is_none() {
+        scene.get_lights().create_light(
+            Some("sun"),
+            Light {
+                cast_shadows: true,
+                ..Light::directional(Vector3::new(0.5, 1.0, 0.25).normalize(), Vector3::repeat(1.0))
+            },
+        );
+    }
+
+    if let Some(path) = argument("--save") {
+        SceneDescription::from_scene(scene)?.save(path)?;
+    }
+
+    Ok(())
+}
+
+// Bloom and a vignette around the tonemapping, which `--tonemap reinhard` and
+// `--exposure` configure; `--lut` adds color grading with the given table.
+fn post_process() -> Vec<Effect> {
+    let operator = match argument("--tonemap").as_deref() {
+        Some("reinhard") => Tonemapping::Reinhard,
+        _ => Tonemapping::Aces,
+    };
+    let exposure = argument("--exposure")
+        .and_then(|exposure| exposure.parse().ok())
+        .unwrap_or(1.0);
+
+    let mut effects = vec![
+        Effect::Bloom {
+            threshold: 1.0,
+            intensity: 0.5,
+        },
+        Effect::Tonemap { operator, exposure },
+    ];
+    effects.extend(argument("--lut").map(|lut| Effect::ColorGrading {
+        lut: PathBuf::from(lut),
+    }));
+    effects.extend([
+        Effect::Fxaa,
+        Effect::Vignette {
+            intensity: 0.3,
+            radius: 0.6,
+        },
+    ]);
+
+    effects
+}
+
+// Four samples per pixel unless `--msaa` asks for another count.
+fn samples() -> u32 {
+    argument("--msaa")
+        .and_then(|samples| samples.parse().ok())
+        .unwrap_or(4)
+}
+
+fn argument(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+fn cube_object(scene: &mut Scene) -> Option<&mut Object> {
+    let group = scene.find_group("basic")?;
+    let group = scene.try_get_group(group)?;
+    let object = group.find_object("cube")?;
+    group.try_get_object(object)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    if let Some(path) = argument("--headless") {
+        let mut engine = EngineBuilder::new()?
+            .post_process(post_process())
+            .msaa(samples())
+            .build_headless([1024, 1024])?;
+
+        load(
+            &mut engine.scene,
+            engine.device.clone(),
+            engine.queue.clone(),
+            engine.render_pass.clone(),
+        )?;
+
+        engine.render()?.save(path)?;
+        return Ok(());
+    }
+
+    let mut engine = EngineBuilder::new()?
+        .instance_with_required_extensions()
+        .post_process(post_process())
+        .msaa(samples())
+        .build()?;
+
+    load(
+        &mut engine.scene,
+        engine.device.clone(),
+        engine.queue.clone(),
+        engine.render_pass.clone(),
+    )?;
+
     let mut ids = vec![];
 
     let mut rng = rand::thread_rng();
@@ -115,20 +263,24 @@ fn main() {
             (ElementState::Pressed, VirtualKeyCode::LControl) => linear_velocity.y = 1.0,
             (ElementState::Released, VirtualKeyCode::LControl) => linear_velocity.y = 0.0,
             (ElementState::Pressed, VirtualKeyCode::E) => {
-                let id = rng.gen::<u32>().to_string();
-
-                scene
-                    .get_group("basic")
-                    .get_object("cube")
-                    .create_instance(&id)
-                    .update(|instance| {
-                        instance.scale = 0.01;
-                        instance.angle = rng.gen::<[f32; 3]>().into();
+                if let Some(object) = cube_object(scene) {
+                    let id = object.create_instance(None);
+
+                    object.get_instance(id).update(|instance| {
+                        instance.scale = Vector3::repeat(0.01);
+                        instance.set_euler_angles(rng.gen::<[f32; 3]>().into());
                         instance.position = rng.gen::<[f32; 3]>().into();
                     });
 
-                ids.push(id);
-                println!("{:?}", ids.len());
+                    ids.push(id);
+                }
+            }
+            (ElementState::Pressed, VirtualKeyCode::Q) => {
+                if let Some(object) = cube_object(scene) {
+                    if let Some(id) = ids.pop() {
+                        object.remove_instance(id);
+                    }
+                }
             }
             _ => (),
         },
@@ -155,17 +307,20 @@ fn main() {
                     .xyz()
             });
 
-            let object = scene.get_group("basic").get_object("cube");
-
-            for id in &ids {
-                object.get_instance(id).update(|instance| {
-                    instance.angle +=
-                        Vector3::new(1.0 * ticks.as_secs_f32(), 1.0 * ticks.as_secs_f32(), 0.0)
-                });
+            if let Some(object) = cube_object(scene) {
+                let angle = 1.0 * ticks.as_secs_f32();
+                let spin = UnitQuaternion::from_euler_angles(angle, angle, 0.0);
+                for &id in &ids {
+                    if let Some(instance) = object.try_get_instance(id) {
+                        instance.update(|instance| instance.rotate(&spin));
+                    }
+                }
             }
 
             last_frame_time = frame_time;
         }
         _ => (),
     });
+
+    Ok(())
 }
@@ -0,0 +1,202 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytemuck::{Pod, Zeroable};
+use image::RgbaImage;
+use nalgebra::{Vector3, Vector4};
+
+use crate::error::EngineError;
+
+// Images embedded in a glTF file, in a buffer view or as a data URI, are
+// decoded when the file is imported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextureSource {
+    Path(PathBuf),
+    Embedded(Arc<RgbaImage>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    pub name: Option<String>,
+
+    pub base_color: Vector4<f32>,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vector3<f32>,
+    pub specular: Vector3<f32>,
+    pub shininess: f32,
+
+    pub base_color_texture: Option<TextureSource>,
+    pub normal_texture: Option<TextureSource>,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            name: None,
+
+            base_color: Vector4::repeat(1.0),
+            metallic: 0.0,
+            roughness: 1.0,
+            emissive: Vector3::zeros(),
+            specular: Vector3::repeat(0.5),
+            shininess: 32.0,
+
+            base_color_texture: None,
+            normal_texture: None,
+        }
+    }
+}
+
+impl Material {
+    // Texture paths are resolved against `base`.
+    pub fn from_mtl(material: &tobj::Material, base: &Path) -> Self {
+        let [r, g, b] = material.diffuse;
+
+        let texture_path = |texture: &str| match texture {
+            "" => None,
+            texture => Some(TextureSource::Path(base.join(texture))),
+        };
+
+        Self {
+            name: Some(material.name.clone()),
+
+            base_color: Vector4::new(r, g, b, material.dissolve),
+            specular: material.specular.into(),
+            shininess: material.shininess,
+            base_color_texture: texture_path(&material.diffuse_texture),
+            normal_texture: texture_path(&material.normal_texture),
+
+            ..Default::default()
+        }
+    }
+
+    // Texture paths are resolved against `base`, buffer views read from the
+    // imported `buffers`. Fails if an embedded image cannot be decoded.
+    pub fn from_gltf(
+        material: &gltf::Material,
+        base: &Path,
+        buffers: &[gltf::buffer::Data],
+    ) -> Result<Self, EngineError> {
+        let pbr = material.pbr_metallic_roughness();
+        let base_color = Vector4::from(pbr.base_color_factor());
+        let metallic = pbr.metallic_factor();
+        let roughness = pbr.roughness_factor();
+
+        // Buffer views are checked against the buffers when importing them.
+        let texture_source = |texture: gltf::Texture| match texture.source().source() {
+            gltf::image::Source::Uri { uri, .. } => match uri.strip_prefix("data:") {
+                Some(data) => Self::decode_data_uri(data),
+                None => Ok(TextureSource::Path(base.join(uri))),
+            },
+            gltf::image::Source::View { view, .. } => {
+                let start = view.offset();
+                let buffer = &buffers[view.buffer().index()];
+                Self::decode_embedded(&buffer[start..start + view.length()])
+            }
+        };
+
+        Ok(Self {
+            name: material.name().map(String::from),
+
+            base_color,
+            metallic,
+            roughness,
+            emissive: material.emissive_factor().into(),
+            // Blinn-Phong approximation of the metallic-roughness model.
+            specular: Vector3::repeat(0.04).lerp(&base_color.xyz(), metallic),
+            shininess: (2.0 / roughness.max(0.01).powi(4) - 2.0).max(1.0),
+
+            base_color_texture: pbr
+                .base_color_texture()
+                .map(|info| texture_source(info.texture()))
+                .transpose()?,
+            normal_texture: material
+                .normal_texture()
+                .map(|normal| texture_source(normal.texture()))
+                .transpose()?,
+        })
+    }
+
+    // Only base64 encoded data is supported, the format is guessed from the
+    // image itself.
+    fn decode_data_uri(data: &str) -> Result<TextureSource, EngineError> {
+        match data.split_once(',') {
+            Some((header, data)) if header.ends_with(";base64") => {
+                Self::decode_embedded(&base64::decode(data)?)
+            }
+            Some((header, _)) => Err(EngineError::UnsupportedTextureEncoding(String::from(
+                header,
+            ))),
+            None => Err(EngineError::UnsupportedTextureEncoding(String::from(data))),
+        }
+    }
+
+    fn decode_embedded(bytes: &[u8]) -> Result<TextureSource, EngineError> {
+        let image = image::load_from_memory(bytes)?;
+
+        Ok(TextureSource::Embedded(Arc::new(image.to_rgba8())))
+    }
+}
+
+// Laid out for a std140 uniform block; the shininess is stored in the w
+// component of the specular color.
+#[repr(C)]
+#[derive(Default, Debug, Copy, Clone, Zeroable, Pod)]
+pub struct MaterialData {
+    base_color: Vector4<f32>,
+    emissive: Vector4<f32>,
+    specular: Vector4<f32>,
+}
+
+impl MaterialData {
+    pub fn new(material: &Material) -> Self {
+        Self {
+            base_color: material.base_color,
+            emissive: material.emissive.push(0.0),
+            specular: material.specular.push(material.shininess),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageOutputFormat, Rgba};
+
+    use super::*;
+
+    #[test]
+    fn decodes_base64_data_uris() {
+        let image = RgbaImage::from_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(image.clone())
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .unwrap();
+        let uri = format!("image/png;base64,{}", base64::encode(png.get_ref()));
+
+        assert_eq!(
+            Material::decode_data_uri(&uri).unwrap(),
+            TextureSource::Embedded(Arc::new(image))
+        );
+    }
+
+    #[test]
+    fn reports_undecodable_data_uris() {
+        assert!(matches!(
+            Material::decode_data_uri("image/png,%89PNG"),
+            Err(EngineError::UnsupportedTextureEncoding(header)) if header == "image/png"
+        ));
+        assert!(matches!(
+            Material::decode_data_uri("image/png;base64,!!"),
+            Err(EngineError::TextureData(_))
+        ));
+        assert!(matches!(
+            Material::decode_data_uri("image/png;base64,AAAA"),
+            Err(EngineError::Texture(_))
+        ));
+    }
+}
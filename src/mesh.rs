@@ -1,11 +1,14 @@
 use std::{
     collections::HashMap,
     io::{BufReader, Cursor},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use bytemuck::{Pod, Zeroable};
-use nalgebra::{Vector2, Vector3};
+use nalgebra::{Vector2, Vector3, Vector4};
+use serde::{Deserialize, Serialize};
+
+use crate::{description::SceneFileError, material::Material};
 
 #[repr(C)]
 #[derive(Default, Debug, Copy, Clone, Zeroable, Pod)]
@@ -13,14 +16,26 @@ pub struct Vertex {
     pub coord: Vector3<f32>,
     pub normal: Vector3<f32>,
     pub tex_coord: Vector2<f32>,
+    // The w component holds the handedness of the bitangent.
+    pub tangent: Vector4<f32>,
 }
 
-vulkano::impl_vertex!(Vertex, coord, normal, tex_coord);
+vulkano::impl_vertex!(Vertex, coord, normal, tex_coord, tangent);
+
+// Identifies the `index`th mesh of an OBJ file, or the `index`th triangle
+// primitive of a glTF file, counted across all of its meshes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeshSource {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub index: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
+    pub material: Option<Material>,
 }
 
 impl Mesh {
@@ -28,39 +43,433 @@ impl Mesh {
         let cursor = Cursor::new(obj);
         let mut reader = BufReader::new(cursor);
 
-        let (models, _) = tobj::load_obj_buf(
-            &mut reader,
-            &tobj::LoadOptions {
-                triangulate: true,
-                single_index: true,
-                ..Default::default()
-            },
-            |p| {
-                mtls.get(p)
-                    .map(Cursor::new)
-                    .map(BufReader::new)
-                    .map(|mut reader| tobj::load_mtl_buf(&mut reader))
-                    .unwrap_or(Err(tobj::LoadError::OpenFileFailed))
-            },
-        )?;
-
-        let meshes = models
+        let (models, materials) = tobj::load_obj_buf(&mut reader, &Self::obj_load_options(), |p| {
+            mtls.get(p)
+                .map(Cursor::new)
+                .map(BufReader::new)
+                .map(|mut reader| tobj::load_mtl_buf(&mut reader))
+                .unwrap_or(Err(tobj::LoadError::OpenFileFailed))
+        })?;
+
+        Ok(Self::from_models(models, materials, Path::new("")))
+    }
+
+    // `mtllib` statements and texture references are resolved relative to the
+    // directory of the OBJ file.
+    pub fn open_obj<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, tobj::LoadError> {
+        let path = path.as_ref();
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let (models, materials) = tobj::load_obj(path, &Self::obj_load_options())?;
+
+        Ok(Self::from_models(models, materials, base))
+    }
+
+    fn obj_load_options() -> tobj::LoadOptions {
+        tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        }
+    }
+
+    // A missing or broken material library leaves the meshes without materials
+    // rather than failing the whole import.
+    fn from_models(
+        models: Vec<tobj::Model>,
+        materials: Result<Vec<tobj::Material>, tobj::LoadError>,
+        base: &Path,
+    ) -> Vec<Self> {
+        let materials: Vec<_> = materials
+            .unwrap_or_default()
+            .iter()
+            .map(|material| Material::from_mtl(material, base))
+            .collect();
+
+        models
             .into_iter()
             .map(|m| {
-                let vertices = (0..m.mesh.positions.len() / 3)
+                let count = m.mesh.positions.len() / 3;
+                let has_normals = m.mesh.normals.len() == count * 3;
+                let has_tex_coords = m.mesh.texcoords.len() == count * 2;
+
+                // Files without `vt` or `vn` lines get zeroed texture
+                // coordinates and smooth normals. Attributes only some faces
+                // refer to cannot be matched to vertices and are ignored. OBJ
+                // puts v = 0 at the bottom of the image, Vulkan at the top.
+                let vertices = (0..count)
                     .map(|p| Vertex {
                         coord: Vector3::from_row_slice(&m.mesh.positions[p * 3..(p + 1) * 3]),
-                        tex_coord: Vector2::from_row_slice(&m.mesh.texcoords[p * 2..(p + 1) * 2]),
-                        normal: Vector3::from_row_slice(&m.mesh.normals[p * 3..(p + 1) * 3]),
+                        tex_coord: m
+                            .mesh
+                            .texcoords
+                            .get(p * 2..(p + 1) * 2)
+                            .filter(|_| has_tex_coords)
+                            .map(|uv| Vector2::new(uv[0], 1.0 - uv[1]))
+                            .unwrap_or_else(Vector2::zeros),
+                        normal: m
+                            .mesh
+                            .normals
+                            .get(p * 3..(p + 1) * 3)
+                            .filter(|_| has_normals)
+                            .map(Vector3::from_row_slice)
+                            .unwrap_or_else(Vector3::zeros),
+                        tangent: Vector4::zeros(),
                     })
                     .collect();
 
                 let indices = m.mesh.indices;
+                let material = m
+                    .mesh
+                    .material_id
+                    .and_then(|id| materials.get(id))
+                    .cloned();
+
+                let mut mesh = Self {
+                    vertices,
+                    indices,
+                    material,
+                };
+                if !has_normals {
+                    mesh.generate_smooth_normals();
+                }
+                // Without texture coordinates there is nothing to derive
+                // tangents from, and the zero tangents keep normal mapping off.
+                if has_tex_coords && !mesh.generate_tangents() {
+                    mesh.clear_tangents();
+                }
+
+                mesh
+            })
+            .collect()
+    }
+
+    // Accepts both .gltf files with external or embedded buffers and binary .glb
+    // files. Every triangle primitive becomes a mesh of its own.
+    pub fn from_gltf<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, SceneFileError> {
+        let path = path.as_ref();
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let gltf::Gltf { document, blob } = gltf::Gltf::open(path)?;
+        let buffers = gltf::import_buffers(&document, Some(base), blob)?;
+
+        let materials = document
+            .materials()
+            .map(|material| Material::from_gltf(&material, base, &buffers))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let meshes = document
+            .meshes()
+            .flat_map(|mesh| mesh.primitives())
+            .filter(Self::is_supported)
+            .map(|primitive| {
+                let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
 
-                Self { vertices, indices }
+                let positions: Vec<[f32; 3]> = reader.read_positions().unwrap().collect();
+                let mut normals = reader.read_normals();
+                let has_normals = normals.is_some();
+                let mut tex_coords = reader.read_tex_coords(0).map(|t| t.into_f32());
+                let has_tex_coords = tex_coords.is_some();
+                let mut tangents = reader.read_tangents();
+                let has_tangents = tangents.is_some();
+
+                let vertices = positions
+                    .iter()
+                    .map(|&coord| Vertex {
+                        coord: coord.into(),
+                        normal: normals
+                            .as_mut()
+                            .and_then(Iterator::next)
+                            .map(Vector3::from)
+                            .unwrap_or_else(Vector3::zeros),
+                        tex_coord: tex_coords
+                            .as_mut()
+                            .and_then(Iterator::next)
+                            .map(Vector2::from)
+                            .unwrap_or_else(Vector2::zeros),
+                        tangent: tangents
+                            .as_mut()
+                            .and_then(Iterator::next)
+                            .map(Vector4::from)
+                            .unwrap_or_else(Vector4::zeros),
+                    })
+                    .collect();
+
+                let indices = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().collect(),
+                    None => (0..positions.len() as u32).collect(),
+                };
+
+                let material = primitive
+                    .material()
+                    .index()
+                    .map(|index| materials[index].clone());
+
+                let mut mesh = Self {
+                    vertices,
+                    indices,
+                    material,
+                };
+                // The glTF specification asks for flat normals when a primitive
+                // has none.
+                if !has_normals {
+                    mesh.generate_flat_normals();
+                }
+                if !has_tangents && has_tex_coords && !mesh.generate_tangents() {
+                    mesh.clear_tangents();
+                }
+
+                mesh
             })
             .collect();
 
         Ok(meshes)
     }
+
+    // Averages the area-weighted normals of the triangles sharing each vertex.
+    pub fn generate_smooth_normals(&mut self) {
+        let mut normals = vec![Vector3::zeros(); self.vertices.len()];
+
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| triangle[i] as usize);
+            if let (Some(va), Some(vb), Some(vc)) = (
+                self.vertices.get(a),
+                self.vertices.get(b),
+                self.vertices.get(c),
+            ) {
+                let normal = (vb.coord - va.coord).cross(&(vc.coord - va.coord));
+                normals[a] += normal;
+                normals[b] += normal;
+                normals[c] += normal;
+            }
+        }
+
+        for (vertex, normal) in self.vertices.iter_mut().zip(normals) {
+            vertex.normal = normal.try_normalize(f32::EPSILON).unwrap_or_else(Vector3::zeros);
+        }
+    }
+
+    // Gives every triangle its own vertices so that each can carry the
+    // triangle's normal.
+    pub fn generate_flat_normals(&mut self) {
+        let vertices: Vec<_> = self
+            .indices
+            .chunks_exact(3)
+            .filter_map(|triangle| {
+                let [a, b, c] = [0, 1, 2].map(|i| self.vertices.get(triangle[i] as usize).copied());
+                let (mut a, mut b, mut c) = (a?, b?, c?);
+
+                let normal = (b.coord - a.coord)
+                    .cross(&(c.coord - a.coord))
+                    .try_normalize(f32::EPSILON)
+                    .unwrap_or_else(Vector3::zeros);
+                a.normal = normal;
+                b.normal = normal;
+                c.normal = normal;
+
+                Some([a, b, c])
+            })
+            .flatten()
+            .collect();
+
+        self.indices = (0..vertices.len() as u32).collect();
+        self.vertices = vertices;
+    }
+
+    // Zero tangents make the shaders fall back to the interpolated normal.
+    pub fn clear_tangents(&mut self) {
+        for vertex in &mut self.vertices {
+            vertex.tangent = Vector4::zeros();
+        }
+    }
+
+    // Tangents follow MikkTSpace, which is what normal maps are baked against by
+    // Blender and most other tools. They are computed per triangle corner, so
+    // the mesh is unwelded first and identical vertices are merged afterwards.
+    // Returns false and leaves the mesh untouched if MikkTSpace fails.
+    pub fn generate_tangents(&mut self) -> bool {
+        // Triangles referring to missing vertices are dropped whole, so that the
+        // corners of the others stay in step.
+        let mut corners = Corners(
+            self.indices
+                .chunks_exact(3)
+                .filter_map(|triangle| {
+                    let [a, b, c] =
+                        [0, 1, 2].map(|i| self.vertices.get(triangle[i] as usize).copied());
+                    Some([a?, b?, c?])
+                })
+                .flatten()
+                .collect(),
+        );
+
+        if !mikktspace::generate_tangents(&mut corners) {
+            return false;
+        }
+
+        let mut welded = HashMap::new();
+        self.vertices.clear();
+        self.indices = corners
+            .0
+            .iter()
+            .map(|vertex| {
+                *welded
+                    .entry(bytemuck::bytes_of(vertex).to_vec())
+                    .or_insert_with(|| {
+                        self.vertices.push(*vertex);
+                        self.vertices.len() as u32 - 1
+                    })
+            })
+            .collect();
+
+        true
+    }
+
+    // Primitives without positions are not rendered, and only triangle lists
+    // are drawn by the pipelines.
+    pub fn is_supported(primitive: &gltf::Primitive) -> bool {
+        primitive.mode() == gltf::mesh::Mode::Triangles
+            && primitive.get(&gltf::Semantic::Positions).is_some()
+    }
+}
+
+// Unindexed triangle list as consumed by the MikkTSpace generator.
+struct Corners(Vec<Vertex>);
+
+impl mikktspace::Geometry for Corners {
+    fn num_faces(&self) -> usize {
+        self.0.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.0[face * 3 + vert].coord.into()
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.0[face * 3 + vert].normal.into()
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.0[face * 3 + vert].tex_coord.into()
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.0[face * 3 + vert].tangent = tangent.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(obj: &str) -> Result<Vec<Mesh>, tobj::LoadError> {
+        Mesh::from_obj(obj, &HashMap::new())
+    }
+
+    fn assert_up_normals(mesh: &Mesh) {
+        for vertex in &mesh.vertices {
+            assert!((vertex.normal - Vector3::z()).norm() < 1e-5, "{:?}", vertex);
+        }
+    }
+
+    #[test]
+    fn obj_without_normals() {
+        let meshes =
+            load("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nf 1/1 2/2 3/3\n").unwrap();
+        let mesh = &meshes[0];
+
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_eq!(mesh.indices.len(), 3);
+        assert_up_normals(mesh);
+        assert!(mesh
+            .vertices
+            .iter()
+            .any(|vertex| vertex.tex_coord == Vector2::new(1.0, 1.0)));
+    }
+
+    #[test]
+    fn obj_tex_coords_flip_v() {
+        let meshes = load(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0.25\nvt 0 1\nf 1/1 2/2 3/3\n",
+        )
+        .unwrap();
+        let mut tex_coords: Vec<_> = meshes[0]
+            .vertices
+            .iter()
+            .map(|vertex| [vertex.tex_coord.x, vertex.tex_coord.y])
+            .collect();
+        tex_coords.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        assert_eq!(tex_coords, [[0.0, 0.0], [0.0, 1.0], [1.0, 0.75]]);
+    }
+
+    #[test]
+    fn obj_without_tex_coords() {
+        let meshes = load("v 0 0 0\nv 1 0 0\nv 0 1 0\nvn 0 0 1\nf 1//1 2//1 3//1\n").unwrap();
+        let mesh = &meshes[0];
+
+        assert_eq!(mesh.vertices.len(), 3);
+        assert_up_normals(mesh);
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|vertex| vertex.tex_coord == Vector2::zeros()
+                && vertex.tangent == Vector4::zeros()));
+    }
+
+    #[test]
+    fn obj_with_partial_tex_coords() {
+        let meshes = load(
+            "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nvt 0.5 0.5\nvt 1 0\nvt 0 1\n\
+             f 1/1 2/2 3/3\nf 2 4 3\n",
+        )
+        .unwrap();
+        let mesh = &meshes[0];
+
+        assert_eq!(mesh.indices.len(), 6);
+        assert_up_normals(mesh);
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|vertex| vertex.tex_coord == Vector2::zeros()));
+    }
+
+    #[test]
+    fn tangents_follow_u() {
+        let mut mesh = Mesh {
+            vertices: [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]
+                .iter()
+                .map(|&[x, y]| Vertex {
+                    coord: Vector3::new(x, y, 0.0),
+                    normal: Vector3::z(),
+                    tex_coord: Vector2::new(x, y),
+                    tangent: Vector4::zeros(),
+                })
+                .collect(),
+            indices: vec![0, 1, 2, 0, 2, 3],
+            material: None,
+        };
+
+        assert!(mesh.generate_tangents());
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices.len(), 6);
+        for vertex in &mesh.vertices {
+            let tangent = vertex.tangent;
+            assert!((tangent.xyz() - Vector3::x()).norm() < 1e-5, "{:?}", vertex);
+            assert_eq!(tangent.w.abs(), 1.0);
+        }
+    }
+
+    #[test]
+    fn obj_with_out_of_range_index() {
+        let result = load("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n");
+
+        assert!(matches!(
+            result,
+            Err(tobj::LoadError::FaceVertexOutOfBounds)
+        ));
+    }
 }
@@ -0,0 +1,392 @@
+use std::{path::PathBuf, sync::Arc};
+
+use vulkano::{
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents},
+    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
+    device::{Device, Queue},
+    format::{Format, NumericType},
+    image::SampleCount,
+    pipeline::{
+        graphics::{
+            input_assembly::InputAssemblyState,
+            vertex_input::BuffersDefinition,
+            viewport::{Viewport, ViewportState},
+        },
+        GraphicsPipeline, Pipeline, PipelineBindPoint,
+    },
+    render_pass::{RenderPass, Subpass},
+    sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo},
+    shader::ShaderModule,
+};
+
+use crate::{
+    error::EngineError,
+    graph::{
+        Attachment, AttachmentId, AttachmentSize, Pass, PassId, RenderGraph, RenderGraphBuilder,
+    },
+    shader,
+    texture::{Texture, TextureFilter, TextureOptions},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Tonemapping {
+    Reinhard,
+    Aces,
+}
+
+// Effects are applied in order to the HDR image the scene is rendered to, the
+// last one writing the target. Those working on displayable colors, such as FXAA,
+// the vignette and color grading, belong after tonemapping.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    // Adds a blurred copy of everything brighter than the threshold.
+    Bloom { threshold: f32, intensity: f32 },
+    Tonemap { operator: Tonemapping, exposure: f32 },
+    Fxaa,
+    // Darkens the image from `radius` outwards, where the corners are at one.
+    Vignette { intensity: f32, radius: f32 },
+    // The lookup table is an image of N slices of N by N texels side by side,
+    // with red increasing to the right, green downwards and blue per slice.
+    ColorGrading { lut: PathBuf },
+}
+
+impl Effect {
+    pub fn default_stack() -> Vec<Self> {
+        vec![
+            Self::Tonemap {
+                operator: Tonemapping::Aces,
+                exposure: 1.0,
+            },
+            Self::Fxaa,
+        ]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    BloomExtract,
+    Blur,
+    BloomComposite,
+    Tonemap,
+    Fxaa,
+    Vignette,
+    ColorGrading,
+}
+
+// A full-screen pass of the stack as declared in the render graph. It samples
+// its inputs at consecutive bindings, followed by the lookup table if any.
+#[derive(Debug, Clone)]
+pub struct PostPass {
+    pass: PassId,
+    kind: Kind,
+    inputs: Vec<AttachmentId>,
+    output: AttachmentId,
+    parameters: [f32; 4],
+    lut: Option<PathBuf>,
+}
+
+struct Stage {
+    pass: PostPass,
+    pipeline: Arc<GraphicsPipeline>,
+    parameters: [f32; 4],
+    lut: Option<Arc<Texture>>,
+}
+
+pub struct PostProcess {
+    stages: Vec<Stage>,
+    sampler: Arc<Sampler>,
+}
+
+impl PostProcess {
+    // The scene is rendered to an attachment of this format when the stack is
+    // not empty; intermediate images use it as well.
+    pub const FORMAT: Format = Format::R16G16B16A16_SFLOAT;
+
+    pub fn declare(
+        graph: &mut RenderGraphBuilder,
+        source: AttachmentId,
+        effects: &[Effect],
+    ) -> Vec<PostPass> {
+        let mut passes = Vec::new();
+        let mut input = source;
+
+        for (index, effect) in effects.iter().enumerate() {
+            let output = match index + 1 == effects.len() {
+                true => graph.target(),
+                false => Self::attachment(graph, AttachmentSize::Target),
+            };
+
+            match *effect {
+                Effect::Bloom {
+                    threshold,
+                    intensity,
+                } => {
+                    // Blurred at half the resolution, horizontally then vertically.
+                    let bright = Self::attachment(graph, AttachmentSize::Downscaled(2));
+                    let half_blurred = Self::attachment(graph, AttachmentSize::Downscaled(2));
+                    let blurred = Self::attachment(graph, AttachmentSize::Downscaled(2));
+
+                    passes.extend([
+                        Self::pass(
+                            graph,
+                            Kind::BloomExtract,
+                            vec![input],
+                            bright,
+                            [threshold, 0.0, 0.0, 0.0],
+                        ),
+                        Self::pass(
+                            graph,
+                            Kind::Blur,
+                            vec![bright],
+                            half_blurred,
+                            [1.0, 0.0, 0.0, 0.0],
+                        ),
+                        Self::pass(
+                            graph,
+                            Kind::Blur,
+                            vec![half_blurred],
+                            blurred,
+                            [0.0, 1.0, 0.0, 0.0],
+                        ),
+                        Self::pass(
+                            graph,
+                            Kind::BloomComposite,
+                            vec![input, blurred],
+                            output,
+                            [intensity, 0.0, 0.0, 0.0],
+                        ),
+                    ]);
+                }
+                Effect::Tonemap { operator, exposure } => {
+                    let operator = match operator {
+                        Tonemapping::Reinhard => 0.0,
+                        Tonemapping::Aces => 1.0,
+                    };
+                    passes.push(Self::pass(
+                        graph,
+                        Kind::Tonemap,
+                        vec![input],
+                        output,
+                        [exposure, operator, 0.0, 0.0],
+                    ));
+                }
+                Effect::Fxaa => {
+                    passes.push(Self::pass(graph, Kind::Fxaa, vec![input], output, [0.0; 4]));
+                }
+                Effect::Vignette { intensity, radius } => {
+                    passes.push(Self::pass(
+                        graph,
+                        Kind::Vignette,
+                        vec![input],
+                        output,
+                        [intensity, radius, 0.0, 0.0],
+                    ));
+                }
+                Effect::ColorGrading { ref lut } => {
+                    passes.push(PostPass {
+                        lut: Some(lut.clone()),
+                        ..Self::pass(graph, Kind::ColorGrading, vec![input], output, [0.0; 4])
+                    });
+                }
+            }
+
+            input = output;
+        }
+
+        passes
+    }
+
+    fn pass(
+        graph: &mut RenderGraphBuilder,
+        kind: Kind,
+        inputs: Vec<AttachmentId>,
+        output: AttachmentId,
+        parameters: [f32; 4],
+    ) -> PostPass {
+        let pass = graph.pass(
+            None,
+            Pass {
+                color: vec![output.into()],
+                depth: None,
+                resolve: vec![],
+                sampled: inputs.clone(),
+                contents: SubpassContents::Inline,
+            },
+        );
+
+        PostPass {
+            pass,
+            kind,
+            inputs,
+            output,
+            parameters,
+            lut: None,
+        }
+    }
+
+    fn attachment(graph: &mut RenderGraphBuilder, size: AttachmentSize) -> AttachmentId {
+        graph.attachment(
+            None,
+            Attachment {
+                format: Self::FORMAT,
+                size,
+                layers: 1,
+                samples: SampleCount::Sample1,
+                clear_value: [0.0; 4].into(),
+            },
+        )
+    }
+
+    // Loads the lookup tables, blocking until they have been uploaded.
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        graph: &RenderGraph,
+        passes: &[PostPass],
+    ) -> Result<Self, EngineError> {
+        let vertex_shader = shader::post::vertex::load(device.clone())?;
+
+        let stages = passes
+            .iter()
+            .map(|pass| {
+                let render_pass = graph.render_pass(pass.pass).ok_or(
+                    EngineError::InvalidRenderGraph("a post-process pass is missing"),
+                )?;
+
+                let fragment_shader = match pass.kind {
+                    Kind::BloomExtract => shader::post::bloom_extract::load(device.clone())?,
+                    Kind::Blur => shader::post::blur::load(device.clone())?,
+                    Kind::BloomComposite => shader::post::bloom_composite::load(device.clone())?,
+                    Kind::Tonemap => shader::post::tonemap::load(device.clone())?,
+                    Kind::Fxaa => shader::post::fxaa::load(device.clone())?,
+                    Kind::Vignette => shader::post::vignette::load(device.clone())?,
+                    Kind::ColorGrading => shader::post::color_grading::load(device.clone())?,
+                };
+                let pipeline = Self::create_pipeline(
+                    device.clone(),
+                    vertex_shader.clone(),
+                    fragment_shader,
+                    render_pass,
+                )?;
+
+                // Targets without an sRGB format get the encoding from the shader.
+                let mut parameters = pass.parameters;
+                let format = graph.attachment(pass.output).unwrap().format;
+                if pass.output == graph.target() && format.type_color() != Some(NumericType::SRGB)
+                {
+                    parameters[3] = 1.0;
+                }
+
+                let lut = pass
+                    .lut
+                    .as_ref()
+                    .map(|path| {
+                        let options = TextureOptions {
+                            srgb: false,
+                            mipmaps: false,
+                            filter: TextureFilter::Bilinear,
+                            anisotropy: None,
+                        };
+                        Texture::open(path, &options, device.clone(), queue.clone())
+                    })
+                    .transpose()?;
+
+                Ok(Stage {
+                    pass: pass.clone(),
+                    pipeline,
+                    parameters,
+                    lut,
+                })
+            })
+            .collect::<Result<_, EngineError>>()?;
+
+        let sampler = Sampler::new(
+            device,
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToEdge; 3],
+                ..Default::default()
+            },
+        )?;
+
+        Ok(Self { stages, sampler })
+    }
+
+    fn create_pipeline(
+        device: Arc<Device>,
+        vertex_shader: Arc<ShaderModule>,
+        fragment_shader: Arc<ShaderModule>,
+        render_pass: Arc<RenderPass>,
+    ) -> Result<Arc<GraphicsPipeline>, EngineError> {
+        let vertex_entry_point = vertex_shader
+            .entry_point("main")
+            .ok_or(EngineError::MissingEntryPoint("main"))?;
+        let fragment_entry_point = fragment_shader
+            .entry_point("main")
+            .ok_or(EngineError::MissingEntryPoint("main"))?;
+        let subpass = Subpass::from(render_pass, 0).ok_or(EngineError::MissingSubpass(0))?;
+
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_state(BuffersDefinition::new())
+            .vertex_shader(vertex_entry_point, ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fragment_entry_point, ())
+            .render_pass(subpass)
+            .build(device)?;
+
+        Ok(pipeline)
+    }
+
+    // Records the stage rendered in `pass`, if the pass belongs to the stack.
+    // The descriptor sets refer to the current images of the graph, so this
+    // has to be recorded again after the graph has been resized.
+    pub fn record(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        graph: &RenderGraph,
+        pass: PassId,
+    ) {
+        let stage = match self.stages.iter().find(|stage| stage.pass.pass == pass) {
+            Some(stage) => stage,
+            None => return,
+        };
+
+        let layout = stage.pipeline.layout().set_layouts().get(0).unwrap();
+        let inputs = stage.pass.inputs.iter().enumerate().map(|(binding, &input)| {
+            WriteDescriptorSet::image_view_sampler(
+                binding as u32,
+                graph.view(input).unwrap(),
+                self.sampler.clone(),
+            )
+        });
+        let lut = stage
+            .lut
+            .iter()
+            .map(|lut| lut.write(stage.pass.inputs.len() as u32));
+        let set = PersistentDescriptorSet::new(layout.clone(), inputs.chain(lut)).unwrap();
+
+        let [width, height] = graph.extent(stage.pass.output).unwrap();
+
+        builder
+            .set_viewport(
+                0,
+                [Viewport {
+                    origin: [0.0, 0.0],
+                    dimensions: [width as f32, height as f32],
+                    depth_range: 0.0..1.0,
+                }],
+            )
+            .bind_pipeline_graphics(stage.pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                stage.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .push_constants(stage.pipeline.layout().clone(), 0, stage.parameters)
+            .draw(3, 1, 0, 0)
+            .unwrap();
+    }
+}
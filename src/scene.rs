@@ -1,22 +1,23 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{ops::Range, sync::Arc};
 
-use nalgebra::{Matrix4, Vector3};
+use bytemuck::Zeroable;
+use nalgebra::{Matrix4, Point3, UnitQuaternion, Vector3};
 use vulkano::{
     buffer::{
-        cpu_pool::CpuBufferPoolSubbuffer, BufferUsage, CpuAccessibleBuffer, CpuBufferPool,
-        TypedBufferAccess,
+        BufferUsage, CpuAccessibleBuffer, CpuBufferPool, DeviceLocalBuffer, TypedBufferAccess,
     },
     command_buffer::{
-        AutoCommandBufferBuilder, CommandBufferInheritanceInfo, CommandBufferUsage,
-        PrimaryAutoCommandBuffer, RenderPassBeginInfo, SecondaryAutoCommandBuffer, SubpassContents,
+        AutoCommandBufferBuilder, BufferCopy, CommandBufferInheritanceInfo, CommandBufferUsage,
+        CopyBufferInfo, DrawIndexedIndirectCommand, PrimaryAutoCommandBuffer,
+        SecondaryAutoCommandBuffer,
     },
     descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
     device::{Device, Queue},
-    memory::pool::StandardMemoryPool,
     pipeline::{
         graphics::{
             depth_stencil::DepthStencilState,
             input_assembly::InputAssemblyState,
+            multisample::MultisampleState,
             rasterization::{CullMode, FrontFace, RasterizationState},
             render_pass::PipelineRenderPassType,
             vertex_input::{BuffersDefinition, VertexMember, VertexMemberTy},
@@ -24,15 +25,51 @@ use vulkano::{
         },
         GraphicsPipeline, Pipeline, PipelineBindPoint, StateMode,
     },
-    render_pass::{Framebuffer, RenderPass, Subpass},
-    shader::ShaderModule, image::{view::ImageView, ImmutableImage},
+    render_pass::{RenderPass, Subpass},
+    image::{ImageViewAbstract, SampleCount},
+    sampler::Sampler,
+    shader::ShaderModule,
+    DeviceSize,
 };
 
 use crate::{
     camera::{Camera, CameraData},
-    mesh::{Mesh, Vertex},
+    error::EngineError,
+    graph::{AttachmentId, PassId, RenderGraph},
+    handle::{Arena, Handle},
+    hierarchy::Hierarchy,
+    light::{Lights, LightsData},
+    postprocess::{PostPass, PostProcess},
+    shadow::ShadowMaps,
+    material::{Material, MaterialData},
+    mesh::{Mesh, MeshSource, Vertex},
+    texture::Texture,
 };
 
+pub type GroupId = Handle<Group>;
+pub type ObjectId = Handle<Object>;
+pub type InstanceId = Handle<Instance>;
+
+// Descriptors that every object of the scene binds in set 0, and the textures
+// bound in place of the ones an object does not have.
+pub struct SharedBindings {
+    pub camera_buffer: Arc<DeviceLocalBuffer<CameraData>>,
+    pub light_buffer: Arc<DeviceLocalBuffer<LightsData>>,
+    pub shadow_map: Arc<dyn ImageViewAbstract>,
+    pub shadow_sampler: Arc<Sampler>,
+    pub default_textures: Vec<Arc<Texture>>,
+}
+
+// The passes of the render graph the scene records into, and the shadow map
+// written by the shadow passes, one layer each.
+#[derive(Debug, Clone)]
+pub struct ScenePasses {
+    pub shadow_map: AttachmentId,
+    pub shadows: Vec<PassId>,
+    pub opaque: PassId,
+    pub post_process: Vec<PostPass>,
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct Model {
@@ -54,64 +91,215 @@ unsafe impl VertexMember for Model {
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+#[derive(Debug, Copy, Clone)]
 pub struct Instance {
     pub position: Vector3<f32>,
-    pub angle: Vector3<f32>,
-    pub scale: f32,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: Vector3<f32>,
 
+    local: Matrix4<f32>,
     model: Model,
 }
 
-vulkano::impl_vertex!(Instance, model);
-
 impl Instance {
     pub fn update<F: FnOnce(&mut Instance)>(&mut self, f: F) {
         f(self);
 
-        self.model = Model {
-            model: Matrix4::new_translation(&self.position)
-                * Matrix4::new_rotation(Vector3::new(self.angle.x, 0.0, 0.0))
-                * Matrix4::new_rotation(Vector3::new(0.0, self.angle.y, 0.0))
-                * Matrix4::new_rotation(Vector3::new(0.0, 0.0, self.angle.z))
-                * Matrix4::new_scaling(self.scale),
+        self.local = Self::model_matrix(&self.position, &self.rotation, &self.scale);
+
+        // Parented instances get their world matrix from the scene before the
+        // next upload.
+        self.model = Model { model: self.local };
+    }
+
+    // Scales first, then rotates, then translates.
+    pub fn model_matrix(
+        position: &Vector3<f32>,
+        rotation: &UnitQuaternion<f32>,
+        scale: &Vector3<f32>,
+    ) -> Matrix4<f32> {
+        Matrix4::new_translation(position)
+            * rotation.to_homogeneous()
+            * Matrix4::new_nonuniform_scaling(scale)
+    }
+
+    // Euler angles in radians, applied around x, then y, then z in the
+    // instance's local frame.
+    pub fn set_euler_angles(&mut self, angles: Vector3<f32>) {
+        self.rotation = UnitQuaternion::from_axis_angle(&Vector3::x_axis(), angles.x)
+            * UnitQuaternion::from_axis_angle(&Vector3::y_axis(), angles.y)
+            * UnitQuaternion::from_axis_angle(&Vector3::z_axis(), angles.z);
+    }
+
+    pub fn euler_angles(&self) -> Vector3<f32> {
+        // The inverse of Rx * Ry * Rz is Rz' * Ry' * Rx', which is the order
+        // nalgebra decomposes into.
+        let (x, y, z) = self.rotation.inverse().euler_angles();
+        -Vector3::new(x, y, z)
+    }
+
+    // Turns the instance so that its local +z axis points at the target.
+    pub fn look_at(&mut self, target: &Point3<f32>, up: &Vector3<f32>) {
+        let direction = target - Point3::from(self.position);
+        if direction.norm_squared() > 0.0 {
+            self.rotation = UnitQuaternion::face_towards(&direction, up);
         }
     }
+
+    pub fn rotate(&mut self, rotation: &UnitQuaternion<f32>) {
+        self.rotation = rotation * self.rotation;
+    }
+
+    pub fn rotate_around(&mut self, point: &Point3<f32>, rotation: &UnitQuaternion<f32>) {
+        self.position = point.coords + rotation * (self.position - point.coords);
+        self.rotate(rotation);
+    }
+
+    pub fn local_matrix(&self) -> Matrix4<f32> {
+        self.local
+    }
+
+    pub fn world_matrix(&self) -> Matrix4<f32> {
+        self.model.model
+    }
+
+    fn data(&self) -> InstanceData {
+        InstanceData { model: self.model }
+    }
 }
 
 impl Default for Instance {
     fn default() -> Self {
         let position = Vector3::new(0.0, 0.0, 0.0);
-        let angle = Vector3::new(0.0, 0.0, 0.0);
-        let scale = 1.0;
+        let rotation = UnitQuaternion::identity();
+        let scale = Vector3::new(1.0, 1.0, 1.0);
 
+        let local = Matrix4::identity();
         let model = Default::default();
 
         Self {
             position,
-            angle,
+            rotation,
             scale,
 
+            local,
             model,
         }
     }
 }
 
+#[repr(C)]
+#[derive(Default, Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct InstanceData {
+    model: Model,
+}
+
+vulkano::impl_vertex!(InstanceData, model);
+
+// The buffers an object rewrites while earlier frames may still read them, and
+// the commands drawing from them. There is one per frame in flight, indexed by
+// the target image the frame renders to, each with its own pending changes.
+struct ObjectFrame {
+    instance_buffer: Arc<DeviceLocalBuffer<[InstanceData]>>,
+    indirect_buffer: Arc<DeviceLocalBuffer<[DrawIndexedIndirectCommand]>>,
+    material_buffer: Arc<DeviceLocalBuffer<MaterialData>>,
+    dirty: Option<Range<usize>>,
+    count_dirty: bool,
+    material_dirty: bool,
+
+    command_buffer: Option<(Viewport, Arc<SecondaryAutoCommandBuffer>)>,
+}
+
+impl ObjectFrame {
+    const INITIAL_CAPACITY: usize = 16;
+
+    // Everything is uploaded to a new frame, which holds `count` instances.
+    fn new(device: Arc<Device>, count: usize) -> Result<Self, EngineError> {
+        let instance_buffer = Self::create_instance_buffer(
+            device.clone(),
+            count.next_power_of_two().max(Self::INITIAL_CAPACITY),
+        )?;
+
+        let indirect_buffer = DeviceLocalBuffer::array(
+            device.clone(),
+            1,
+            BufferUsage {
+                indirect_buffer: true,
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            device.active_queue_family_indices().iter().copied(),
+        )?;
+
+        let material_buffer = DeviceLocalBuffer::new(
+            device.clone(),
+            BufferUsage {
+                uniform_buffer: true,
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            device.active_queue_family_indices().iter().copied(),
+        )?;
+
+        Ok(Self {
+            instance_buffer,
+            indirect_buffer,
+            material_buffer,
+            dirty: Some(0..count),
+            count_dirty: true,
+            material_dirty: true,
+            command_buffer: None,
+        })
+    }
+
+    fn create_instance_buffer(
+        device: Arc<Device>,
+        capacity: usize,
+    ) -> Result<Arc<DeviceLocalBuffer<[InstanceData]>>, EngineError> {
+        let buffer = DeviceLocalBuffer::array(
+            device.clone(),
+            capacity as DeviceSize,
+            BufferUsage {
+                vertex_buffer: true,
+                transfer_dst: true,
+                ..BufferUsage::empty()
+            },
+            device.active_queue_family_indices().iter().copied(),
+        )?;
+
+        Ok(buffer)
+    }
+}
+
+// The instance buffer is kept dense: `draws` lists the instances in buffer
+// order, and removing one moves the last into its place, like a swap remove.
+// `draw_indices` maps arena slots back to positions in `draws`.
 pub struct Object {
-    instances: HashMap<String, Instance>,
+    source: Option<MeshSource>,
+    material: Material,
+    instances: Arena<Instance>,
+    draws: Vec<InstanceId>,
+    draw_indices: Vec<usize>,
 
     vertex_buffer: Arc<CpuAccessibleBuffer<[Vertex]>>,
     index_buffer: Arc<CpuAccessibleBuffer<[u32]>>,
-    instance_buffer: CpuBufferPool<Instance>,
-//    texture_image: ImageView<ImmutableImage>,
+    instance_staging_buffer: CpuBufferPool<InstanceData>,
+    indirect_staging_buffer: CpuBufferPool<DrawIndexedIndirectCommand>,
+    material_staging_buffer: CpuBufferPool<MaterialData>,
+    textures: Vec<Option<Arc<Texture>>>,
 
-    command_buffer: Option<Arc<SecondaryAutoCommandBuffer>>,
+    // Created as frames first upload or record.
+    frames: Vec<ObjectFrame>,
 }
 
 impl Object {
-    pub fn new(mesh: Mesh, device: Arc<Device>) -> Self {
-        let instances = HashMap::new();
+
+    pub fn new(mesh: Mesh, device: Arc<Device>) -> Result<Self, EngineError> {
+        let source = None;
+        let material = mesh.material.unwrap_or_default();
+        let instances = Arena::new();
+        let draws = Vec::new();
+        let draw_indices = Vec::new();
 
         let vertex_buffer = CpuAccessibleBuffer::from_iter(
             device.clone(),
@@ -121,8 +309,7 @@ impl Object {
             },
             false,
             mesh.vertices,
-        )
-        .unwrap();
+        )?;
 
         let index_buffer = CpuAccessibleBuffer::from_iter(
             device.clone(),
@@ -132,20 +319,121 @@ impl Object {
             },
             false,
             mesh.indices,
-        )
-        .unwrap();
-
-        let instance_buffer = CpuBufferPool::vertex_buffer(device);
+        )?;
 
-        let command_buffer = None;
+        let instance_staging_buffer = CpuBufferPool::upload(device.clone());
+        let indirect_staging_buffer = CpuBufferPool::upload(device.clone());
+        let material_staging_buffer = CpuBufferPool::upload(device);
+        let textures = Vec::new();
+        let frames = Vec::new();
 
-        Self {
+        Ok(Self {
+            source,
+            material,
             instances,
+            draws,
+            draw_indices,
             vertex_buffer,
             index_buffer,
-            instance_buffer,
-            command_buffer,
+            instance_staging_buffer,
+            indirect_staging_buffer,
+            material_staging_buffer,
+            textures,
+            frames,
+        })
+    }
+
+    // Makes sure the object has buffers for the first `count` frames.
+    pub(crate) fn create_frames(
+        &mut self,
+        device: Arc<Device>,
+        count: usize,
+    ) -> Result<(), EngineError> {
+        while self.frames.len() < count {
+            self.frames
+                .push(ObjectFrame::new(device.clone(), self.draws.len())?);
+        }
+
+        Ok(())
+    }
+
+    // Records the copies needed to bring the device-local instance, indirect and
+    // material buffers of `frame` up to date. Only the slots touched since the
+    // frame last uploaded are copied.
+    pub fn upload(
+        &mut self,
+        device: Arc<Device>,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        frame: usize,
+    ) -> Result<(), EngineError> {
+        self.create_frames(device.clone(), frame + 1)?;
+        let data = &mut self.frames[frame];
+
+        if self.draws.len() > data.instance_buffer.len() as usize {
+            let capacity = self.draws.len().next_power_of_two();
+            data.instance_buffer = ObjectFrame::create_instance_buffer(device, capacity)?;
+            data.dirty = Some(0..self.draws.len());
+            data.command_buffer = None;
         }
+
+        if let Some(range) = data.dirty.take() {
+            let range = range.start..range.end.min(self.draws.len());
+
+            if !range.is_empty() {
+                let size = std::mem::size_of::<InstanceData>() as DeviceSize;
+                let staging = self.instance_staging_buffer.from_iter(
+                    self.draws[range.clone()].iter().map(|&id| {
+                        self.instances
+                            .get(id)
+                            .map(Instance::data)
+                            .unwrap_or_else(InstanceData::zeroed)
+                    }),
+                )?;
+
+                builder
+                    .copy_buffer(CopyBufferInfo {
+                        regions: vec![BufferCopy {
+                            src_offset: 0,
+                            dst_offset: range.start as DeviceSize * size,
+                            size: range.len() as DeviceSize * size,
+                            ..Default::default()
+                        }]
+                        .into(),
+                        ..CopyBufferInfo::buffers(staging, data.instance_buffer.clone())
+                    })
+                    .unwrap();
+            }
+        }
+
+        if data.count_dirty {
+            data.count_dirty = false;
+
+            let staging = self.indirect_staging_buffer.from_data(DrawIndexedIndirectCommand {
+                index_count: self.index_buffer.len() as u32,
+                instance_count: self.draws.len() as u32,
+                first_index: 0,
+                vertex_offset: 0,
+                first_instance: 0,
+            })?;
+
+            builder
+                .copy_buffer(CopyBufferInfo::buffers(staging, data.indirect_buffer.clone()))
+                .unwrap();
+        }
+
+        if data.material_dirty {
+            data.material_dirty = false;
+
+            let staging = self
+                .material_staging_buffer
+                .from_data(MaterialData::new(&self.material))?;
+
+            builder
+                .copy_buffer(CopyBufferInfo::buffers(staging, data.material_buffer.clone()))
+                .unwrap();
+        }
+
+        Ok(())
     }
 
     pub fn command_buffer(
@@ -153,83 +441,251 @@ impl Object {
         device: Arc<Device>,
         queue: Arc<Queue>,
         pipeline: Arc<GraphicsPipeline>,
-        camera_buffer: Arc<CpuBufferPoolSubbuffer<CameraData, Arc<StandardMemoryPool>>>,
+        bindings: &SharedBindings,
+        viewport: &Viewport,
+        frame: usize,
     ) -> Arc<SecondaryAutoCommandBuffer> {
-        if self.command_buffer.is_none() {
-            let instance_subbuffer = self
-                .instance_buffer
-                .from_iter(self.instances.clone().into_values())
-                .unwrap();
+        self.create_frames(device.clone(), frame + 1).unwrap();
+        let data = &self.frames[frame];
 
-            let mut builder = AutoCommandBufferBuilder::secondary(
-                device,
-                queue.queue_family_index(),
-                CommandBufferUsage::SimultaneousUse,
-                CommandBufferInheritanceInfo {
-                    render_pass: Some(match pipeline.render_pass() {
-                        PipelineRenderPassType::BeginRenderPass(render_pass) => {
-                            render_pass.clone().into()
-                        }
-                        PipelineRenderPassType::BeginRendering(_) => panic!(),
-                    }),
-                    ..Default::default()
-                },
-            )
+        if let Some((recorded_viewport, command_buffer)) = &data.command_buffer {
+            if recorded_viewport == viewport {
+                return command_buffer.clone();
+            }
+        }
+
+        let mut builder = AutoCommandBufferBuilder::secondary(
+            device,
+            queue.queue_family_index(),
+            CommandBufferUsage::SimultaneousUse,
+            CommandBufferInheritanceInfo {
+                render_pass: Some(match pipeline.render_pass() {
+                    PipelineRenderPassType::BeginRenderPass(render_pass) => {
+                        render_pass.clone().into()
+                    }
+                    PipelineRenderPassType::BeginRendering(_) => panic!(),
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let layout = pipeline.layout().set_layouts().get(0).unwrap();
+        let writes = layout.bindings().keys().map(|&binding| match binding {
+            0 => WriteDescriptorSet::buffer(0, bindings.camera_buffer.clone()),
+            1 => WriteDescriptorSet::buffer(1, bindings.light_buffer.clone()),
+            binding => WriteDescriptorSet::image_view_sampler(
+                binding,
+                bindings.shadow_map.clone(),
+                bindings.shadow_sampler.clone(),
+            ),
+        });
+        let set = PersistentDescriptorSet::new(layout.clone(), writes).unwrap();
+
+        builder
+            .set_viewport(0, [viewport.clone()])
+            .bind_pipeline_graphics(pipeline.clone())
+            .bind_vertex_buffers(0, (self.vertex_buffer.clone(), data.instance_buffer.clone()))
+            .bind_index_buffer(self.index_buffer.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                0,
+                set,
+            );
+
+        // Only pipelines whose shaders declare the material block have a
+        // second set. Samplers in it without a texture get the default one.
+        if let Some(layout) = pipeline.layout().set_layouts().get(1) {
+            let writes = layout.bindings().keys().map(|&binding| match binding {
+                0 => WriteDescriptorSet::buffer(0, data.material_buffer.clone()),
+                binding => {
+                    let defaults = &bindings.default_textures;
+                    let slot = binding as usize - 1;
+                    self.texture(slot)
+                        .or_else(|| defaults.get(slot).map(Arc::as_ref))
+                        .unwrap_or(&*defaults[Texture::BASE_COLOR])
+                        .write(binding)
+                }
+            });
+            let set = PersistentDescriptorSet::new(layout.clone(), writes).unwrap();
+
+            builder.bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                pipeline.layout().clone(),
+                1,
+                set,
+            );
+        }
+
+        builder
+            .draw_indexed_indirect(data.indirect_buffer.clone())
             .unwrap();
 
-            let layout = pipeline.layout().set_layouts().get(0).unwrap();
-            let set = PersistentDescriptorSet::new(
-                layout.clone(),
-                [WriteDescriptorSet::buffer(0, camera_buffer)],
-            )
+        let command_buffer = Arc::new(builder.build().unwrap());
+        self.frames[frame].command_buffer = Some((viewport.clone(), command_buffer.clone()));
+
+        command_buffer
+    }
+
+    // Draws the instances with whatever pipeline is bound, which only has to
+    // consume positions and models. The frame has to be created already.
+    pub(crate) fn record_depth(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        frame: usize,
+    ) {
+        let data = &self.frames[frame];
+
+        builder
+            .bind_vertex_buffers(0, (self.vertex_buffer.clone(), data.instance_buffer.clone()))
+            .bind_index_buffer(self.index_buffer.clone())
+            .draw_indexed_indirect(data.indirect_buffer.clone())
             .unwrap();
+    }
 
-            builder
-                .bind_pipeline_graphics(pipeline.clone())
-                .bind_vertex_buffers(0, self.vertex_buffer.clone())
-                .bind_vertex_buffers(1, instance_subbuffer)
-                .bind_index_buffer(self.index_buffer.clone())
-                .bind_descriptor_sets(
-                    PipelineBindPoint::Graphics,
-                    pipeline.layout().clone(),
-                    0,
-                    set,
-                )
-                .draw_indexed(
-                    self.index_buffer.len() as u32,
-                    self.instances.len() as u32,
-                    0,
-                    0,
-                    0,
+    pub fn is_recorded(&self, viewport: &Viewport) -> bool {
+        !self.frames.is_empty()
+            && self.frames.iter().all(|data| {
+                matches!(
+                    &data.command_buffer,
+                    Some((recorded_viewport, _)) if recorded_viewport == viewport
                 )
-                .unwrap();
+            })
+    }
 
-            self.command_buffer = Some(Arc::new(builder.build().unwrap()));
-        }
+    // Where the mesh was loaded from, so the object can be written to a scene file.
+    pub fn source(&self) -> Option<&MeshSource> {
+        self.source.as_ref()
+    }
 
-        self.command_buffer.clone().unwrap()
+    pub fn set_source(&mut self, source: MeshSource) {
+        self.source = Some(source);
     }
 
-    pub fn create_instance(&mut self, id: &str) -> &mut Instance {
-        self.instances.insert(String::from(id), Instance::default());
-        self.get_instance(id)
+    pub fn material(&self) -> &Material {
+        &self.material
     }
 
-    pub fn get_instance(&mut self, id: &str) -> &mut Instance {
+    pub fn set_material(&mut self, material: Material) {
+        self.material = material;
+        for data in &mut self.frames {
+            data.material_dirty = true;
+        }
+    }
+
+    pub fn texture(&self, slot: usize) -> Option<&Texture> {
+        self.textures.get(slot)?.as_deref()
+    }
+
+    pub fn set_texture(&mut self, slot: usize, texture: Option<Arc<Texture>>) {
+        if self.textures.len() <= slot {
+            self.textures.resize(slot + 1, None);
+        }
+        self.textures[slot] = texture;
         self.invalidate();
-        self.instances.get_mut(id).unwrap()
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = (InstanceId, &Instance)> {
+        self.instances.iter()
+    }
+
+    pub fn instance_name(&self, id: InstanceId) -> Option<&str> {
+        self.instances.name(id)
+    }
+
+    pub fn create_instance(&mut self, name: Option<&str>) -> InstanceId {
+        let id = self.instances.insert(name, Instance::default());
+
+        if self.draw_indices.len() <= id.index() {
+            self.draw_indices.resize(id.index() + 1, 0);
+        }
+        self.draw_indices[id.index()] = self.draws.len();
+        self.draws.push(id);
+
+        self.mark_dirty(id);
+        self.mark_count_dirty();
+        id
+    }
+
+    pub fn get_instance(&mut self, id: InstanceId) -> &mut Instance {
+        self.try_get_instance(id).unwrap()
+    }
+
+    pub fn try_get_instance(&mut self, id: InstanceId) -> Option<&mut Instance> {
+        if self.instances.contains(id) {
+            self.mark_dirty(id);
+        }
+        self.instances.get_mut(id)
+    }
+
+    pub fn instance(&self, id: InstanceId) -> Option<&Instance> {
+        self.instances.get(id)
+    }
+
+    pub fn find_instance(&self, name: &str) -> Option<InstanceId> {
+        self.instances.find(name)
+    }
+
+    pub fn contains_instance(&self, id: InstanceId) -> bool {
+        self.instances.contains(id)
+    }
+
+    fn set_world_matrix(&mut self, id: InstanceId, world: Matrix4<f32>) {
+        if let Some(instance) = self.instances.get_mut(id) {
+            if instance.model.model != world {
+                instance.model = Model { model: world };
+                self.mark_dirty(id);
+            }
+        }
+    }
+
+    pub fn remove_instance(&mut self, id: InstanceId) -> bool {
+        let removed = self.instances.remove(id).is_some();
+        if removed {
+            let index = self.draw_indices[id.index()];
+            self.draws.swap_remove(index);
+
+            if let Some(&moved) = self.draws.get(index) {
+                self.draw_indices[moved.index()] = index;
+                self.mark_dirty(moved);
+            }
+            self.mark_count_dirty();
+        }
+        removed
+    }
+
+    fn mark_dirty(&mut self, id: InstanceId) {
+        let index = self.draw_indices[id.index()];
+        for data in &mut self.frames {
+            data.dirty = Some(match data.dirty.take() {
+                Some(range) => range.start.min(index)..range.end.max(index + 1),
+                None => index..index + 1,
+            });
+        }
+    }
+
+    fn mark_count_dirty(&mut self) {
+        for data in &mut self.frames {
+            data.count_dirty = true;
+        }
     }
 
     fn invalidate(&mut self) {
-        self.command_buffer = None;
+        for data in &mut self.frames {
+            data.command_buffer = None;
+        }
     }
 }
 
 pub struct Group {
-    objects: HashMap<String, Object>,
+    objects: Arena<Object>,
     pipeline: Arc<GraphicsPipeline>,
     vertex_shader: Arc<ShaderModule>,
     fragment_shader: Arc<ShaderModule>,
+    shader_name: Option<String>,
+
+    recorded: bool,
 }
 
 impl Group {
@@ -238,38 +694,41 @@ impl Group {
         vertex_shader: Arc<ShaderModule>,
         fragment_shader: Arc<ShaderModule>,
         render_pass: Arc<RenderPass>,
-        viewport: Viewport,
-    ) -> Self {
-        let objects = HashMap::new();
+    ) -> Result<Self, EngineError> {
+        let objects = Arena::new();
         let pipeline = Self::create_pipeline(
             device,
             vertex_shader.clone(),
             fragment_shader.clone(),
             render_pass,
-            viewport,
-        );
+        )?;
+        let shader_name = None;
+        let recorded = false;
 
-        Self {
+        Ok(Self {
             objects,
             pipeline,
             vertex_shader,
             fragment_shader,
-        }
+            shader_name,
+            recorded,
+        })
     }
 
     pub fn recreate_pipeline(
         &mut self,
         device: Arc<Device>,
         render_pass: Arc<RenderPass>,
-        viewport: Viewport,
-    ) {
+    ) -> Result<(), EngineError> {
         self.pipeline = Self::create_pipeline(
             device,
             self.vertex_shader.clone(),
             self.fragment_shader.clone(),
             render_pass,
-            viewport,
-        );
+        )?;
+        self.invalidate();
+
+        Ok(())
     }
 
     fn create_pipeline(
@@ -277,109 +736,279 @@ impl Group {
         vertex_shader: Arc<ShaderModule>,
         fragment_shader: Arc<ShaderModule>,
         render_pass: Arc<RenderPass>,
-        viewport: Viewport,
-    ) -> Arc<GraphicsPipeline> {
-        GraphicsPipeline::start()
+    ) -> Result<Arc<GraphicsPipeline>, EngineError> {
+        let vertex_entry_point = vertex_shader
+            .entry_point("main")
+            .ok_or(EngineError::MissingEntryPoint("main"))?;
+        let fragment_entry_point = fragment_shader
+            .entry_point("main")
+            .ok_or(EngineError::MissingEntryPoint("main"))?;
+        let subpass = Subpass::from(render_pass, 0).ok_or(EngineError::MissingSubpass(0))?;
+
+        // Matches the sample count of the attachments the pass renders to.
+        let multisample_state = MultisampleState {
+            rasterization_samples: subpass.num_samples().unwrap_or(SampleCount::Sample1),
+            ..MultisampleState::new()
+        };
+
+        let pipeline = GraphicsPipeline::start()
             .vertex_input_state(
                 BuffersDefinition::new()
                     .vertex::<Vertex>()
-                    .instance::<Instance>(),
+                    .instance::<InstanceData>(),
             )
-            .vertex_shader(vertex_shader.entry_point("main").unwrap(), ())
+            .vertex_shader(vertex_entry_point, ())
             .input_assembly_state(InputAssemblyState::new())
-            .viewport_state(ViewportState::viewport_fixed_scissor_irrelevant([viewport]))
-            .fragment_shader(fragment_shader.entry_point("main").unwrap(), ())
-            .render_pass(Subpass::from(render_pass, 0).unwrap())
+            .viewport_state(ViewportState::viewport_dynamic_scissor_irrelevant())
+            .fragment_shader(fragment_entry_point, ())
+            .multisample_state(multisample_state)
+            .render_pass(subpass)
             .rasterization_state(RasterizationState {
                 cull_mode: StateMode::Fixed(CullMode::Back),
                 front_face: StateMode::Fixed(FrontFace::CounterClockwise),
                 ..Default::default()
             })
             .depth_stencil_state(DepthStencilState::simple_depth_test())
-            .build(device)
-            .unwrap()
+            .build(device)?;
+
+        Ok(pipeline)
     }
 
     pub fn command_buffers(
         &mut self,
         device: Arc<Device>,
         queue: Arc<Queue>,
-        camera_buffer: Arc<CpuBufferPoolSubbuffer<CameraData, Arc<StandardMemoryPool>>>,
+        bindings: &SharedBindings,
+        viewport: &Viewport,
+        frame: usize,
     ) -> Vec<Arc<SecondaryAutoCommandBuffer>> {
+        self.recorded = true;
         self.objects
-            .iter_mut()
-            .map(|(_, object)| {
+            .values_mut()
+            .map(|object| {
                 object.command_buffer(
                     device.clone(),
                     queue.clone(),
                     self.pipeline.clone(),
-                    camera_buffer.clone(),
+                    bindings,
+                    viewport,
+                    frame,
                 )
             })
             .collect()
     }
 
-    pub fn create_object(&mut self, id: &str, mesh: Mesh, device: Arc<Device>) -> &mut Object {
-        self.objects
-            .insert(String::from(id), Object::new(mesh, device));
-        self.get_object(id)
+    pub fn upload(
+        &mut self,
+        device: Arc<Device>,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        frame: usize,
+    ) -> Result<(), EngineError> {
+        for object in self.objects.values_mut() {
+            object.upload(device.clone(), builder, frame)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn create_frames(
+        &mut self,
+        device: Arc<Device>,
+        count: usize,
+    ) -> Result<(), EngineError> {
+        for object in self.objects.values_mut() {
+            object.create_frames(device.clone(), count)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn is_recorded(&self, viewport: &Viewport) -> bool {
+        self.recorded
+            && self
+                .objects
+                .values()
+                .all(|object| object.is_recorded(viewport))
+    }
+
+    // The name under which the shaders are registered in `shader::load`, so the
+    // group can be written to a scene file.
+    pub fn shader_name(&self) -> Option<&str> {
+        self.shader_name.as_deref()
+    }
+
+    pub fn set_shader_name(&mut self, name: &str) {
+        self.shader_name = Some(String::from(name));
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = (ObjectId, &Object)> {
+        self.objects.iter()
     }
 
-    pub fn get_object(&mut self, id: &str) -> &mut Object {
-        self.objects.get_mut(id).unwrap()
+    fn object_values(&self) -> impl Iterator<Item = &Object> + Clone {
+        self.objects.values()
+    }
+
+    pub fn object_name(&self, id: ObjectId) -> Option<&str> {
+        self.objects.name(id)
+    }
+
+    pub fn create_object(
+        &mut self,
+        name: Option<&str>,
+        mesh: Mesh,
+        device: Arc<Device>,
+    ) -> Result<ObjectId, EngineError> {
+        let id = self.objects.insert(name, Object::new(mesh, device)?);
+        self.recorded = false;
+        Ok(id)
+    }
+
+    pub fn get_object(&mut self, id: ObjectId) -> &mut Object {
+        self.try_get_object(id).unwrap()
+    }
+
+    pub fn try_get_object(&mut self, id: ObjectId) -> Option<&mut Object> {
+        self.objects.get_mut(id)
+    }
+
+    pub fn object(&self, id: ObjectId) -> Option<&Object> {
+        self.objects.get(id)
+    }
+
+    pub fn find_object(&self, name: &str) -> Option<ObjectId> {
+        self.objects.find(name)
+    }
+
+    pub fn contains_object(&self, id: ObjectId) -> bool {
+        self.objects.contains(id)
+    }
+
+    // The buffers of a removed object stay alive for as long as a command buffer
+    // still in flight holds a reference to them.
+    pub fn remove_object(&mut self, id: ObjectId) -> bool {
+        let removed = self.objects.remove(id).is_some();
+        if removed {
+            self.recorded = false;
+        }
+        removed
     }
 
     fn invalidate(&mut self) {
-        for (_, object) in self.objects.iter_mut() {
+        self.recorded = false;
+        for object in self.objects.values_mut() {
             object.invalidate();
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct InstanceRef {
+    pub group: GroupId,
+    pub object: ObjectId,
+    pub instance: InstanceId,
+}
+
 pub struct Scene {
-    groups: HashMap<String, Group>,
+    groups: Arena<Group>,
     camera: Camera,
+    lights: Lights,
+    hierarchy: Hierarchy<InstanceRef>,
+    default_textures: Vec<Arc<Texture>>,
+    shadow_maps: ShadowMaps,
+    post_process: PostProcess,
+    passes: ScenePasses,
 
     command_buffers: Option<Vec<Arc<PrimaryAutoCommandBuffer>>>,
+    recorded_shadows: usize,
 }
 
 impl Scene {
-    pub fn new(device: Arc<Device>) -> Self {
-        let groups = HashMap::new();
-        let camera = Camera::new(device);
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        graph: &RenderGraph,
+        passes: ScenePasses,
+    ) -> Result<Self, EngineError> {
+        let groups = Arena::new();
+        let camera = Camera::new(device.clone(), graph.target_count())?;
+        let lights = Lights::new(device.clone(), graph.target_count())?;
+        let hierarchy = Hierarchy::new();
+        let default_textures = Texture::defaults(device.clone(), queue.clone())?;
+        let shadow_render_pass = passes
+            .shadows
+            .first()
+            .and_then(|&pass| graph.render_pass(pass))
+            .ok_or(EngineError::InvalidRenderGraph("the scene has no shadow pass"))?;
+        let shadow_maps = ShadowMaps::new(device.clone(), shadow_render_pass)?;
+        let post_process = PostProcess::new(device, queue, graph, &passes.post_process)?;
         let command_buffers = None;
+        let recorded_shadows = 0;
 
-        Self {
+        Ok(Self {
             groups,
             camera,
+            lights,
+            hierarchy,
+            default_textures,
+            shadow_maps,
+            post_process,
+            passes,
             command_buffers,
-        }
+            recorded_shadows,
+        })
     }
 
     pub fn recreate_pipeline(
         &mut self,
         device: Arc<Device>,
         render_pass: Arc<RenderPass>,
-        viewport: Viewport,
-    ) {
-        for (_, group) in self.groups.iter_mut() {
-            group.recreate_pipeline(device.clone(), render_pass.clone(), viewport.clone());
+    ) -> Result<(), EngineError> {
+        for group in self.groups.values_mut() {
+            group.recreate_pipeline(device.clone(), render_pass.clone())?;
         }
+        self.invalidate();
+
+        Ok(())
     }
 
     pub fn command_buffers(
         &mut self,
         device: Arc<Device>,
         queue: Arc<Queue>,
-        framebuffers: &[Arc<Framebuffer>],
+        graph: &RenderGraph,
+        viewport: &Viewport,
     ) -> Vec<Arc<PrimaryAutoCommandBuffer>> {
+        let shadows = self.lights.shadow_count();
+
+        if !self.groups.values().all(|group| group.is_recorded(viewport))
+            || shadows != self.recorded_shadows
+        {
+            self.invalidate();
+        }
+
         if self.command_buffers.is_none() {
-            let camera_buffer = self.camera.subbuffer();
+            self.recorded_shadows = shadows;
+
+            // The shadow passes draw straight from the object buffers of each
+            // frame, so they have to exist before recording.
+            for group in self.groups.values_mut() {
+                group
+                    .create_frames(device.clone(), graph.target_count())
+                    .unwrap();
+            }
 
             self.command_buffers = Some(
-                framebuffers
-                    .iter()
-                    .map(|framebuffer| {
+                (0..graph.target_count())
+                    .map(|target| {
+                        let bindings = SharedBindings {
+                            camera_buffer: self.camera.buffer(target),
+                            light_buffer: self.lights.buffer(target),
+                            shadow_map: graph.view(self.passes.shadow_map).unwrap(),
+                            shadow_sampler: self.shadow_maps.sampler(),
+                            default_textures: self.default_textures.clone(),
+                        };
+
                         let mut builder = AutoCommandBufferBuilder::primary(
                             device.clone(),
                             queue.queue_family_index(),
@@ -387,30 +1016,40 @@ impl Scene {
                         )
                         .unwrap();
 
-                        builder
-                            .begin_render_pass(
-                                RenderPassBeginInfo {
-                                    clear_values: vec![
-                                        Some([0.3, 0.3, 0.3, 1.0].into()),
-                                        Some(1.0.into()),
-                                    ],
-                                    ..RenderPassBeginInfo::framebuffer(framebuffer.clone())
-                                },
-                                SubpassContents::SecondaryCommandBuffers,
-                            )
-                            .unwrap();
-
-                        for (_, group) in self.groups.iter_mut() {
-                            builder
-                                .execute_commands_from_vec(group.command_buffers(
-                                    device.clone(),
-                                    queue.clone(),
-                                    camera_buffer.clone(),
-                                ))
-                                .unwrap();
-                        }
-
-                        builder.end_render_pass().unwrap();
+                        let shadow_layer = |pass: PassId| {
+                            self.passes.shadows.iter().position(|&id| id == pass)
+                        };
+                        // Layers past the shadow casting lights are not sampled,
+                        // so their passes are left out.
+                        let unused = |pass: PassId| {
+                            shadow_layer(pass).map_or(false, |layer| layer >= shadows)
+                        };
+
+                        graph.record(&mut builder, target, unused, |pass, builder| {
+                            if let Some(layer) = shadow_layer(pass) {
+                                self.shadow_maps.record(
+                                    builder,
+                                    layer,
+                                    self.lights.buffer(target),
+                                    target,
+                                    self.groups.values().flat_map(Group::object_values),
+                                );
+                            } else if pass == self.passes.opaque {
+                                for group in self.groups.values_mut() {
+                                    builder
+                                        .execute_commands_from_vec(group.command_buffers(
+                                            device.clone(),
+                                            queue.clone(),
+                                            &bindings,
+                                            viewport,
+                                            target,
+                                        ))
+                                        .unwrap();
+                                }
+                            } else {
+                                self.post_process.record(builder, graph, pass);
+                            }
+                        });
 
                         Arc::new(builder.build().unwrap())
                     })
@@ -421,47 +1060,253 @@ impl Scene {
         self.command_buffers.clone().unwrap()
     }
 
+    // Has to be executed before the command buffer returned by `command_buffers`
+    // for the same `frame`, which reads the camera, light and instance data it
+    // uploads. Only the buffers of that frame are written, so other frames in
+    // flight can keep reading theirs.
+    pub fn upload_command_buffer(
+        &mut self,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        frame: usize,
+    ) -> Result<PrimaryAutoCommandBuffer, EngineError> {
+        let mut builder = AutoCommandBufferBuilder::primary(
+            device.clone(),
+            queue.queue_family_index(),
+            CommandBufferUsage::OneTimeSubmit,
+        )
+        .unwrap();
+
+        self.camera.upload(&mut builder, frame)?;
+        self.lights
+            .upload(&mut builder, self.camera.configuration(), frame)?;
+        self.propagate_transforms();
+
+        for group in self.groups.values_mut() {
+            group.upload(device.clone(), &mut builder, frame)?;
+        }
+
+        Ok(builder.build().unwrap())
+    }
+
+    pub fn instance(&self, instance: InstanceRef) -> Option<&Instance> {
+        self.group(instance.group)?
+            .object(instance.object)?
+            .instance(instance.instance)
+    }
+
+    pub fn try_get_instance(&mut self, instance: InstanceRef) -> Option<&mut Instance> {
+        self.try_get_group(instance.group)?
+            .try_get_object(instance.object)?
+            .try_get_instance(instance.instance)
+    }
+
+    pub fn parent(&self, child: InstanceRef) -> Option<InstanceRef> {
+        self.hierarchy.parent(child)
+    }
+
+    // The child's transform becomes relative to the parent's world matrix.
+    // Parents may live in any object or group; cycles are rejected.
+    pub fn set_parent(
+        &mut self,
+        child: InstanceRef,
+        parent: Option<InstanceRef>,
+    ) -> Result<(), EngineError> {
+        if self.instance(child).is_none() {
+            return Err(EngineError::InvalidParent);
+        }
+
+        if let Some(parent) = parent {
+            if self.instance(parent).is_none() {
+                return Err(EngineError::InvalidParent);
+            }
+        }
+
+        self.hierarchy.set_parent(child, parent)?;
+
+        if parent.is_none() {
+            if let Some(instance) = self.try_get_instance(child) {
+                instance.update(|_| ());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn propagate_transforms(&mut self) {
+        let groups = &self.groups;
+        let worlds = self.hierarchy.world_matrices(|instance| {
+            groups
+                .get(instance.group)?
+                .object(instance.object)?
+                .instance(instance.instance)
+                .map(Instance::local_matrix)
+        });
+
+        for (child, world) in worlds {
+            if let Some(object) = self
+                .groups
+                .get_mut(child.group)
+                .and_then(|group| group.try_get_object(child.object))
+            {
+                object.set_world_matrix(child.instance, world);
+            }
+        }
+    }
+
     pub fn create_group(
         &mut self,
-        id: &str,
+        name: Option<&str>,
         device: Arc<Device>,
         vertex_shader: Arc<ShaderModule>,
         fragment_shader: Arc<ShaderModule>,
         render_pass: Arc<RenderPass>,
-        viewport: Viewport,
-    ) -> &mut Group {
-        self.groups.insert(
-            String::from(id),
-            Group::new(
-                device,
-                vertex_shader,
-                fragment_shader,
-                render_pass,
-                viewport,
-            ),
-        );
-        self.get_group(id)
+    ) -> Result<GroupId, EngineError> {
+        let group = Group::new(
+            device,
+            vertex_shader,
+            fragment_shader,
+            render_pass,
+        )?;
+        Ok(self.groups.insert(name, group))
+    }
+
+    pub fn groups(&self) -> impl Iterator<Item = (GroupId, &Group)> {
+        self.groups.iter()
     }
 
-    pub fn get_group(&mut self, id: &str) -> &mut Group {
+    pub fn group_name(&self, id: GroupId) -> Option<&str> {
+        self.groups.name(id)
+    }
+
+    pub fn get_group(&mut self, id: GroupId) -> &mut Group {
+        self.try_get_group(id).unwrap()
+    }
+
+    pub fn try_get_group(&mut self, id: GroupId) -> Option<&mut Group> {
+        self.groups.get_mut(id)
+    }
+
+    pub fn group(&self, id: GroupId) -> Option<&Group> {
+        self.groups.get(id)
+    }
+
+    pub fn find_group(&self, name: &str) -> Option<GroupId> {
+        self.groups.find(name)
+    }
+
+    pub fn contains_group(&self, id: GroupId) -> bool {
+        self.groups.contains(id)
+    }
+
+    pub fn remove_group(&mut self, id: GroupId) -> bool {
+        let removed = self.groups.remove(id).is_some();
+        if removed {
+            self.invalidate();
+        }
+        removed
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.lights.clear();
         self.invalidate();
-        self.groups.get_mut(id).unwrap()
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
     }
 
     pub fn get_camera(&mut self) -> &mut Camera {
-        self.invalidate_all();
         &mut self.camera
     }
 
-    fn invalidate(&mut self) {
+    pub fn lights(&self) -> &Lights {
+        &self.lights
+    }
+
+    pub fn get_lights(&mut self) -> &mut Lights {
+        &mut self.lights
+    }
+
+    pub(crate) fn invalidate(&mut self) {
         self.command_buffers = None;
     }
+}
 
-    fn invalidate_all(&mut self) {
-        self.invalidate();
-        self.camera.invalidate();
-        for (_, group) in self.groups.iter_mut() {
-            group.invalidate()
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn assert_close(actual: Vector3<f32>, expected: Vector3<f32>) {
+        assert!(
+            (actual - expected).norm() < 1e-5,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn model_matrix_scales_rotates_then_translates() {
+        let model = Instance::model_matrix(
+            &Vector3::new(1.0, 2.0, 3.0),
+            &UnitQuaternion::from_axis_angle(&Vector3::z_axis(), FRAC_PI_2),
+            &Vector3::new(2.0, 1.0, 1.0),
+        );
+
+        // (1, 1, 0) scales to (2, 1, 0), rotates to (-1, 2, 0) and moves to (0, 4, 3).
+        let point = model.transform_point(&Point3::new(1.0, 1.0, 0.0));
+        assert_close(point.coords, Vector3::new(0.0, 4.0, 3.0));
+    }
+
+    #[test]
+    fn euler_angles_round_trip() {
+        let mut instance = Instance::default();
+
+        for angles in [
+            Vector3::new(0.3, -0.5, 1.2),
+            Vector3::new(-1.0, 0.7, -2.5),
+            Vector3::new(0.0, 0.0, 0.0),
+        ] {
+            instance.set_euler_angles(angles);
+            assert_close(instance.euler_angles(), angles);
         }
     }
+
+    #[test]
+    fn incremental_rotations_pass_a_quarter_turn() {
+        let mut instance = Instance::default();
+        let step = UnitQuaternion::from_euler_angles(0.1, 0.1, 0.0);
+
+        // Past a quarter turn around y, Euler angles flip to another
+        // decomposition, so spinning by adding to them would jump.
+        for _ in 0..20 {
+            instance.update(|instance| instance.rotate(&step));
+        }
+
+        let expected = step.powf(20.0);
+        assert!(instance.rotation.angle_to(&expected) < 1e-4);
+        assert!(instance.rotation.angle() > FRAC_PI_2);
+
+        let forward = instance.world_matrix().transform_vector(&Vector3::z());
+        assert_close(forward, expected * Vector3::z());
+    }
+
+    #[test]
+    fn look_at_points_forward_at_target() {
+        let mut instance = Instance {
+            position: Vector3::new(1.0, 2.0, 3.0),
+            ..Default::default()
+        };
+        let target = Point3::new(4.0, -2.0, 3.0);
+
+        instance.look_at(&target, &Vector3::y());
+
+        let forward = instance.rotation * Vector3::z();
+        let direction = (target.coords - instance.position).normalize();
+        assert_close(forward, direction);
+    }
 }
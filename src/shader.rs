@@ -1,3 +1,30 @@
+use std::sync::Arc;
+
+use vulkano::{
+    device::Device,
+    shader::{ShaderCreationError, ShaderModule},
+};
+
+// Looks up a vertex and fragment shader pair by the name scene files refer to.
+pub fn load(
+    name: &str,
+    device: Arc<Device>,
+) -> Result<Option<(Arc<ShaderModule>, Arc<ShaderModule>)>, ShaderCreationError> {
+    let modules = match name {
+        "simple" => (
+            simple::vertex::load(device.clone())?,
+            simple::fragment::load(device)?,
+        ),
+        "raymarch" => (
+            raymarch::vertex::load(device.clone())?,
+            raymarch::fragment::load(device)?,
+        ),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(modules))
+}
+
 pub mod simple {
     pub mod vertex {
         vulkano_shaders::shader! {
@@ -8,6 +35,7 @@ pub mod simple {
 layout(location = 0) in vec3 coord;
 layout(location = 1) in vec3 normal;
 layout(location = 2) in vec2 tex_coord;
+layout(location = 3) in vec4 tangent;
 
 layout(location = 4) in mat4 model;
 
@@ -16,12 +44,197 @@ layout(set = 0, binding = 0) uniform Camera {
     mat4 projection;
 } camera;
 
-layout(location = 0) out vec3 fragColor;
+layout(location = 0) out vec3 fragNormal;
+layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec4 fragTangent;
+layout(location = 3) out vec3 fragPosition;
 
 void main() {
     mat4 viewModel = camera.view * model;
     gl_Position = camera.projection * viewModel * vec4(coord, 1.0);
-    fragColor = abs(/*viewModel */ vec4(normal, 1.0)).xyz;
+    fragPosition = (model * vec4(coord, 1.0)).xyz;
+    fragNormal = mat3(transpose(inverse(model))) * normal;
+    fragTexCoord = tex_coord;
+    fragTangent = vec4(mat3(model) * tangent.xyz, tangent.w);
+}
+"
+        }
+    }
+
+    pub mod fragment {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            src: "
+#version 450
+
+layout(location = 0) in vec3 fragNormal;
+layout(location = 1) in vec2 fragTexCoord;
+layout(location = 2) in vec4 fragTangent;
+layout(location = 3) in vec3 fragPosition;
+layout(location = 0) out vec4 f_color;
+
+// Have to match `Lights::MAX_LIGHTS` and `ShadowMaps::MAX_SHADOWS`.
+#define MAX_LIGHTS 16
+#define MAX_SHADOWS 4
+
+#define DIRECTIONAL 0.0
+#define SPOT 2.0
+
+const vec3 AMBIENT = vec3(0.05);
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 view;
+    mat4 projection;
+} camera;
+
+struct Light {
+    vec4 position;
+    vec4 direction;
+    vec4 color;
+    vec4 cone;
+    vec4 shadow;
+};
+
+layout(set = 0, binding = 1) uniform Lights {
+    uint count;
+    Light lights[MAX_LIGHTS];
+    mat4 shadow_matrices[MAX_SHADOWS];
+} lights;
+
+layout(set = 0, binding = 2) uniform sampler2DArrayShadow shadow_map;
+
+layout(set = 1, binding = 0) uniform Material {
+    vec4 base_color;
+    vec4 emissive;
+    vec4 specular;
+} material;
+layout(set = 1, binding = 1) uniform sampler2D base_color_texture;
+layout(set = 1, binding = 2) uniform sampler2D normal_texture;
+
+// Meshes without texture coordinates, or whose tangents could not be
+// generated, have zero tangents and keep their interpolated normal.
+vec3 surface_normal() {
+    vec3 n = normalize(fragNormal);
+    vec3 t = fragTangent.xyz - n * dot(n, fragTangent.xyz);
+    if (dot(t, t) == 0.0) {
+        return n;
+    }
+
+    t = normalize(t);
+    vec3 b = cross(n, t) * fragTangent.w;
+    vec3 mapped = texture(normal_texture, fragTexCoord).xyz * 2.0 - 1.0;
+    return normalize(mat3(t, b, n) * mapped);
+}
+
+// Averages 3x3 comparisons, each of which is filtered over 2x2 texels.
+float shadow(Light light) {
+    if (light.shadow.x < 0.0) {
+        return 1.0;
+    }
+
+    vec4 clip = lights.shadow_matrices[int(light.shadow.x)] * vec4(fragPosition, 1.0);
+    vec3 ndc = clip.xyz / clip.w;
+    if (ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    vec2 uv = ndc.xy * 0.5 + 0.5;
+    vec2 texel = 1.0 / vec2(textureSize(shadow_map, 0).xy);
+
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            lit += texture(shadow_map, vec4(uv + vec2(x, y) * texel, light.shadow.x, ndc.z));
+        }
+    }
+
+    return lit / 9.0;
+}
+
+// Blinn-Phong, with the light falling off smoothly to zero at its range.
+vec3 shade(Light light, vec3 n, vec3 v, vec3 albedo) {
+    vec3 l = -light.direction.xyz;
+    float attenuation = 1.0;
+
+    if (light.position.w != DIRECTIONAL) {
+        vec3 to_light = light.position.xyz - fragPosition;
+        float dist = length(to_light);
+        l = to_light / dist;
+
+        float falloff = clamp(1.0 - pow(dist / light.direction.w, 4.0), 0.0, 1.0);
+        attenuation = falloff * falloff / (dist * dist + 1.0);
+
+        if (light.position.w == SPOT) {
+            float cos_angle = dot(-l, light.direction.xyz);
+            attenuation *= smoothstep(light.cone.y, light.cone.x, cos_angle);
+        }
+    }
+
+    float diffuse = max(dot(n, l), 0.0);
+    float specular = 0.0;
+    if (diffuse > 0.0) {
+        vec3 h = normalize(l + v);
+        specular = pow(max(dot(n, h), 0.0), material.specular.w);
+    }
+
+    attenuation *= shadow(light);
+
+    return light.color.rgb * attenuation * (diffuse * albedo + specular * material.specular.rgb);
+}
+
+void main() {
+    vec4 base_color = material.base_color * texture(base_color_texture, fragTexCoord);
+    vec3 n = surface_normal();
+    vec3 eye = inverse(camera.view)[3].xyz;
+    vec3 v = normalize(eye - fragPosition);
+
+    vec3 color = AMBIENT * base_color.rgb + material.emissive.rgb;
+    for (uint i = 0; i < min(lights.count, uint(MAX_LIGHTS)); i++) {
+        color += shade(lights.lights[i], n, v, base_color.rgb);
+    }
+
+    f_color = vec4(color, base_color.a);
+}
+"
+        }
+    }
+}
+
+pub mod shadow {
+    pub mod vertex {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            src: "
+#version 450
+
+// Have to match `Lights::MAX_LIGHTS` and `ShadowMaps::MAX_SHADOWS`.
+#define MAX_LIGHTS 16
+#define MAX_SHADOWS 4
+
+layout(location = 0) in vec3 coord;
+
+layout(location = 4) in mat4 model;
+
+struct Light {
+    vec4 position;
+    vec4 direction;
+    vec4 color;
+    vec4 cone;
+    vec4 shadow;
+};
+
+layout(set = 0, binding = 1) uniform Lights {
+    uint count;
+    Light lights[MAX_LIGHTS];
+    mat4 shadow_matrices[MAX_SHADOWS];
+} lights;
+
+layout(push_constant) uniform Shadow {
+    uint layer;
+} shadow;
+
+void main() {
+    gl_Position = lights.shadow_matrices[shadow.layer] * model * vec4(coord, 1.0);
 }
 "
         }
@@ -33,17 +246,268 @@ void main() {
             src: "
 #version 450
 
-layout(location = 0) in vec3 fragColor;
+void main() {
+}
+"
+        }
+    }
+}
+
+pub mod post {
+    // A triangle covering the viewport, with texture coordinates running from
+    // zero to one across it.
+    pub mod vertex {
+        vulkano_shaders::shader! {
+            ty: "vertex",
+            src: "
+#version 450
+
+layout(location = 0) out vec2 uv;
+
+void main() {
+    uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+"
+        }
+    }
+
+    pub mod bloom_extract {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+#include <post.glsl>
+
+void main() {
+    vec3 color = texture(source, uv).rgb;
+    float brightness = max(color.r, max(color.g, color.b));
+    float weight = max(brightness - post.parameters.x, 0.0) / max(brightness, 0.0001);
+    f_color = encode(color * weight);
+}
+"
+        }
+    }
+
+    pub mod blur {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+#include <post.glsl>
+
+// A 9 tap gaussian in 5 bilinear samples along the direction in xy.
+const float offsets[3] = float[](0.0, 1.3846153846, 3.2307692308);
+const float weights[3] = float[](0.2270270270, 0.3162162162, 0.0702702703);
+
+void main() {
+    vec2 texel = post.parameters.xy / vec2(textureSize(source, 0));
+    vec3 color = texture(source, uv).rgb * weights[0];
+    for (int i = 1; i < 3; i++) {
+        color += texture(source, uv + texel * offsets[i]).rgb * weights[i];
+        color += texture(source, uv - texel * offsets[i]).rgb * weights[i];
+    }
+    f_color = encode(color);
+}
+"
+        }
+    }
+
+    pub mod bloom_composite {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1) uniform sampler2D bloom;
+
+#include <post.glsl>
+
+void main() {
+    vec3 color = texture(source, uv).rgb + texture(bloom, uv).rgb * post.parameters.x;
+    f_color = encode(color);
+}
+"
+        }
+    }
+
+    pub mod tonemap {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+#include <post.glsl>
+
+vec3 reinhard(vec3 color) {
+    return color / (1.0 + color);
+}
+
+// Narkowicz's fit of the ACES filmic curve.
+vec3 aces(vec3 color) {
+    return (color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14);
+}
+
+void main() {
+    vec3 color = max(texture(source, uv).rgb * post.parameters.x, 0.0);
+    f_color = encode(post.parameters.y > 0.5 ? aces(color) : reinhard(color));
+}
+"
+        }
+    }
+
+    pub mod fxaa {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
 layout(location = 0) out vec4 f_color;
 
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+#include <post.glsl>
+
+#define REDUCE_MIN (1.0 / 128.0)
+#define REDUCE_MUL (1.0 / 8.0)
+#define SPAN_MAX 8.0
+
+// Colors are linear here; the square root approximates perceived brightness.
+float luma(vec3 color) {
+    return sqrt(dot(color, vec3(0.299, 0.587, 0.114)));
+}
+
 void main() {
-    f_color = vec4(fragColor, 1.0);
+    vec2 texel = 1.0 / vec2(textureSize(source, 0));
+
+    vec3 middle = texture(source, uv).rgb;
+    float luma_nw = luma(texture(source, uv + vec2(-1.0, -1.0) * texel).rgb);
+    float luma_ne = luma(texture(source, uv + vec2(1.0, -1.0) * texel).rgb);
+    float luma_sw = luma(texture(source, uv + vec2(-1.0, 1.0) * texel).rgb);
+    float luma_se = luma(texture(source, uv + vec2(1.0, 1.0) * texel).rgb);
+    float luma_m = luma(middle);
+
+    float luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    float luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    vec2 direction = vec2(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se)
+    );
+    float reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * REDUCE_MUL, REDUCE_MIN);
+    float scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, -SPAN_MAX, SPAN_MAX) * texel;
+
+    vec3 near = 0.5 * (
+        texture(source, uv + direction * (1.0 / 3.0 - 0.5)).rgb +
+        texture(source, uv + direction * (2.0 / 3.0 - 0.5)).rgb
+    );
+    vec3 far = near * 0.5 + 0.25 * (
+        texture(source, uv - direction * 0.5).rgb +
+        texture(source, uv + direction * 0.5).rgb
+    );
+
+    float luma_far = luma(far);
+    f_color = encode(luma_far < luma_min || luma_far > luma_max ? near : far);
 }
 "
         }
     }
+
+    pub mod vignette {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+
+#include <post.glsl>
+
+void main() {
+    // Scaled so the corners are at distance one from the center.
+    float distance_to_center = length(uv - 0.5) * sqrt(2.0);
+    float amount = smoothstep(post.parameters.y, 1.0, distance_to_center) * post.parameters.x;
+    f_color = encode(texture(source, uv).rgb * (1.0 - amount));
+}
+"
+        }
+    }
+
+    pub mod color_grading {
+        vulkano_shaders::shader! {
+            ty: "fragment",
+            include: ["src/glsl"],
+            src: "
+#version 450
+
+layout(location = 0) in vec2 uv;
+layout(location = 0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform sampler2D source;
+layout(set = 0, binding = 1) uniform sampler2D lut;
+
+#include <post.glsl>
+
+vec3 to_srgb(vec3 color) {
+    return mix(12.92 * color, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, color));
 }
 
+vec3 to_linear(vec3 color) {
+    return mix(color / 12.92, pow((color + 0.055) / 1.055, vec3(2.4)), step(0.04045, color));
+}
+
+// The table is indexed by and stores sRGB encoded colors, as image editors
+// produce it. Its slices of constant blue are laid out side by side.
+void main() {
+    vec3 color = to_srgb(clamp(texture(source, uv).rgb, 0.0, 1.0));
+
+    float size = float(textureSize(lut, 0).y);
+    float slice = color.b * (size - 1.0);
+    float lower = floor(slice);
+    float upper = min(lower + 1.0, size - 1.0);
+
+    vec2 coord = (color.rg * (size - 1.0) + 0.5) / vec2(size * size, size);
+    vec3 a = texture(lut, coord + vec2(lower / size, 0.0)).rgb;
+    vec3 b = texture(lut, coord + vec2(upper / size, 0.0)).rgb;
+
+    f_color = encode(to_linear(mix(a, b, slice - lower)));
+}
+"
+        }
+    }
+}
 
 pub mod raymarch {
     pub mod vertex {
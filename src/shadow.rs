@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use vulkano::{
+    buffer::DeviceLocalBuffer,
+    command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer, SubpassContents},
+    descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet},
+    device::Device,
+    format::Format,
+    image::SampleCount,
+    pipeline::{
+        graphics::{
+            depth_stencil::{CompareOp, DepthStencilState},
+            input_assembly::InputAssemblyState,
+            rasterization::{CullMode, DepthBias, DepthBiasState, RasterizationState},
+            vertex_input::BuffersDefinition,
+            viewport::{Viewport, ViewportState},
+        },
+        GraphicsPipeline, Pipeline, PipelineBindPoint, StateMode,
+    },
+    render_pass::{RenderPass, Subpass},
+    sampler::{BorderColor, Filter, Sampler, SamplerAddressMode, SamplerCreateInfo},
+};
+
+use crate::{
+    error::EngineError,
+    graph::{
+        Attachment, AttachmentId, AttachmentLayer, AttachmentSize, Pass, PassId,
+        RenderGraphBuilder,
+    },
+    light::LightsData,
+    mesh::Vertex,
+    scene::{InstanceData, Object},
+    shader,
+};
+
+// One layer of a depth array image per shadow-casting light, rendered from the
+// light's point of view before the main pass and sampled with depth comparison.
+pub struct ShadowMaps {
+    pipeline: Arc<GraphicsPipeline>,
+    sampler: Arc<Sampler>,
+}
+
+impl ShadowMaps {
+    pub const MAX_SHADOWS: usize = 4;
+    pub const SIZE: u32 = 2048;
+    const FORMAT: Format = Format::D16_UNORM;
+
+    // Declares the shadow map array and a pass rendering each of its layers.
+    pub fn declare(graph: &mut RenderGraphBuilder) -> (AttachmentId, Vec<PassId>) {
+        let shadow_map = graph.attachment(
+            Some("shadow map"),
+            Attachment {
+                format: Self::FORMAT,
+                size: AttachmentSize::Fixed([Self::SIZE; 2]),
+                layers: Self::MAX_SHADOWS as u32,
+                samples: SampleCount::Sample1,
+                clear_value: 1.0.into(),
+            },
+        );
+
+        let passes = (0..Self::MAX_SHADOWS as u32)
+            .map(|layer| {
+                graph.pass(
+                    Some(&format!("shadow {}", layer)),
+                    Pass {
+                        color: vec![],
+                        depth: Some(AttachmentLayer {
+                            attachment: shadow_map,
+                            layer,
+                        }),
+                        resolve: vec![],
+                        sampled: vec![],
+                        contents: SubpassContents::Inline,
+                    },
+                )
+            })
+            .collect();
+
+        (shadow_map, passes)
+    }
+
+    // All shadow passes have the same attachments, so `render_pass` can be
+    // that of any of them.
+    pub fn new(device: Arc<Device>, render_pass: Arc<RenderPass>) -> Result<Self, EngineError> {
+        // Comparison with linear filtering gives 2x2 percentage closer
+        // filtering per tap; outside the map everything is lit.
+        let sampler = Sampler::new(
+            device.clone(),
+            SamplerCreateInfo {
+                mag_filter: Filter::Linear,
+                min_filter: Filter::Linear,
+                address_mode: [SamplerAddressMode::ClampToBorder; 3],
+                border_color: BorderColor::FloatOpaqueWhite,
+                compare: Some(CompareOp::LessOrEqual),
+                ..Default::default()
+            },
+        )?;
+
+        let pipeline = Self::create_pipeline(device, render_pass)?;
+
+        Ok(Self { pipeline, sampler })
+    }
+
+    fn create_pipeline(
+        device: Arc<Device>,
+        render_pass: Arc<RenderPass>,
+    ) -> Result<Arc<GraphicsPipeline>, EngineError> {
+        let vertex_shader = shader::shadow::vertex::load(device.clone())?;
+        let fragment_shader = shader::shadow::fragment::load(device.clone())?;
+
+        let vertex_entry_point = vertex_shader
+            .entry_point("main")
+            .ok_or(EngineError::MissingEntryPoint("main"))?;
+        let fragment_entry_point = fragment_shader
+            .entry_point("main")
+            .ok_or(EngineError::MissingEntryPoint("main"))?;
+        let subpass = Subpass::from(render_pass, 0).ok_or(EngineError::MissingSubpass(0))?;
+
+        let size = Self::SIZE as f32;
+        let pipeline = GraphicsPipeline::start()
+            .vertex_input_state(
+                BuffersDefinition::new()
+                    .vertex::<Vertex>()
+                    .instance::<InstanceData>(),
+            )
+            .vertex_shader(vertex_entry_point, ())
+            .input_assembly_state(InputAssemblyState::new())
+            .viewport_state(ViewportState::viewport_fixed_scissor_irrelevant([Viewport {
+                origin: [0.0, 0.0],
+                dimensions: [size, size],
+                depth_range: 0.0..1.0,
+            }]))
+            .fragment_shader(fragment_entry_point, ())
+            .render_pass(subpass)
+            // Both faces are drawn, so the bias has to keep lit surfaces from
+            // shadowing themselves.
+            .rasterization_state(RasterizationState {
+                cull_mode: StateMode::Fixed(CullMode::None),
+                depth_bias: Some(DepthBiasState {
+                    enable_dynamic: false,
+                    bias: StateMode::Fixed(DepthBias {
+                        constant_factor: 1.25,
+                        clamp: 0.0,
+                        slope_factor: 1.75,
+                    }),
+                }),
+                ..Default::default()
+            })
+            .depth_stencil_state(DepthStencilState::simple_depth_test())
+            .build(device)?;
+
+        Ok(pipeline)
+    }
+
+    pub fn sampler(&self) -> Arc<Sampler> {
+        self.sampler.clone()
+    }
+
+    // Renders one layer inside its pass; the light matrices are read from the
+    // light buffer, so the recorded commands stay valid while lights move.
+    pub fn record<'a>(
+        &self,
+        builder: &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>,
+        layer: usize,
+        light_buffer: Arc<DeviceLocalBuffer<LightsData>>,
+        frame: usize,
+        objects: impl Iterator<Item = &'a Object>,
+    ) {
+        let layout = self.pipeline.layout().set_layouts().get(0).unwrap();
+        let set = PersistentDescriptorSet::new(
+            layout.clone(),
+            [WriteDescriptorSet::buffer(1, light_buffer)],
+        )
+        .unwrap();
+
+        builder
+            .bind_pipeline_graphics(self.pipeline.clone())
+            .bind_descriptor_sets(
+                PipelineBindPoint::Graphics,
+                self.pipeline.layout().clone(),
+                0,
+                set,
+            )
+            .push_constants(self.pipeline.layout().clone(), 0, layer as u32);
+
+        for object in objects {
+            object.record_depth(builder, frame);
+        }
+    }
+}
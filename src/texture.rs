@@ -1,19 +1,175 @@
-/*
-use vulkano::sampler::Sampler;
+use std::{path::Path, sync::Arc};
+
+use image::RgbaImage;
+use vulkano::{
+    descriptor_set::WriteDescriptorSet,
+    device::{Device, Queue},
+    format::Format,
+    image::{view::ImageView, ImageDimensions, ImmutableImage, MipmapsCount},
+    sampler::{
+        Filter, Sampler, SamplerAddressMode, SamplerCreateInfo, SamplerMipmapMode,
+        LOD_CLAMP_NONE,
+    },
+    sync::GpuFuture,
+};
+
+use crate::error::EngineError;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    Trilinear,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureOptions {
+    // Color textures are stored in sRGB, data such as normal maps linearly.
+    pub srgb: bool,
+    // Generates the full mip chain by blitting each level from the previous one.
+    pub mipmaps: bool,
+    pub filter: TextureFilter,
+    // Clamped to what the device supports; ignored without the
+    // `sampler_anisotropy` feature.
+    pub anisotropy: Option<f32>,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            srgb: true,
+            mipmaps: true,
+            filter: TextureFilter::Trilinear,
+            anisotropy: None,
+        }
+    }
+}
 
 pub struct Texture {
-    sampler: Option<Sampler>,
+    image_view: Arc<ImageView<ImmutableImage>>,
+    sampler: Arc<Sampler>,
 }
 
 impl Texture {
-    pub fn new() -> Self {
-        let sampler = None;
+    // Texture slot `i` of an object is bound to binding `i + 1` of the material
+    // set, after the material block.
+    pub const BASE_COLOR: usize = 0;
+    pub const NORMAL: usize = 1;
 
-        Self {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        options: &TextureOptions,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    ) -> Result<Arc<Self>, EngineError> {
+        let image = image::open(path)?.to_rgba8();
+        Self::from_image(&image, options, device, queue)
+    }
+
+    // Blocks until the image has been uploaded.
+    pub fn from_image(
+        image: &RgbaImage,
+        options: &TextureOptions,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    ) -> Result<Arc<Self>, EngineError> {
+        let format = match options.srgb {
+            true => Format::R8G8B8A8_SRGB,
+            false => Format::R8G8B8A8_UNORM,
+        };
+
+        let (image, future) = ImmutableImage::from_iter(
+            image.as_raw().iter().copied(),
+            ImageDimensions::Dim2d {
+                width: image.width(),
+                height: image.height(),
+                array_layers: 1,
+            },
+            match options.mipmaps {
+                true => MipmapsCount::Log2,
+                false => MipmapsCount::One,
+            },
+            format,
+            queue,
+        )?;
+        future.then_signal_fence_and_flush()?.wait(None)?;
+
+        let image_view = ImageView::new_default(image)?;
+
+        let (filter, mipmap_mode) = match options.filter {
+            TextureFilter::Nearest => (Filter::Nearest, SamplerMipmapMode::Nearest),
+            TextureFilter::Bilinear => (Filter::Linear, SamplerMipmapMode::Nearest),
+            TextureFilter::Trilinear => (Filter::Linear, SamplerMipmapMode::Linear),
+        };
+
+        let anisotropy = options
+            .anisotropy
+            .filter(|_| device.enabled_features().sampler_anisotropy)
+            .map(|anisotropy| {
+                let max = device.physical_device().properties().max_sampler_anisotropy;
+                anisotropy.clamp(1.0, max)
+            });
+
+        let sampler = Sampler::new(
+            device,
+            SamplerCreateInfo {
+                mag_filter: filter,
+                min_filter: filter,
+                mipmap_mode,
+                address_mode: [SamplerAddressMode::Repeat; 3],
+                anisotropy,
+                lod: 0.0..=LOD_CLAMP_NONE,
+                ..Default::default()
+            },
+        )?;
+
+        Ok(Arc::new(Self {
+            image_view,
             sampler,
-        }
+        }))
+    }
+
+    pub fn solid(
+        color: [u8; 4],
+        options: &TextureOptions,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+    ) -> Result<Arc<Self>, EngineError> {
+        let image = RgbaImage::from_pixel(1, 1, image::Rgba(color));
+        Self::from_image(&image, options, device, queue)
     }
 
-    
+    // Bound, per slot, wherever an object has no texture of its own: white for
+    // the base color and an unperturbed normal for the normal map.
+    pub fn defaults(device: Arc<Device>, queue: Arc<Queue>) -> Result<Vec<Arc<Self>>, EngineError> {
+        let white = Self::solid(
+            [255; 4],
+            &TextureOptions::default(),
+            device.clone(),
+            queue.clone(),
+        )?;
+        let flat_normal = Self::solid(
+            [128, 128, 255, 255],
+            &TextureOptions {
+                srgb: false,
+                ..Default::default()
+            },
+            device,
+            queue,
+        )?;
+
+        Ok(vec![white, flat_normal])
+    }
+
+    pub fn image_view(&self) -> Arc<ImageView<ImmutableImage>> {
+        self.image_view.clone()
+    }
+
+    pub fn sampler(&self) -> Arc<Sampler> {
+        self.sampler.clone()
+    }
+
+    pub fn write(&self, binding: u32) -> WriteDescriptorSet {
+        WriteDescriptorSet::image_view_sampler(binding, self.image_view(), self.sampler())
+    }
 }
-*/